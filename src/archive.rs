@@ -0,0 +1,366 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, Timelike};
+use clap::ValueEnum;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use schemars::JsonSchema;
+use serde_derive::Deserialize;
+use walkdir::WalkDir;
+use zip::{ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    logger::{debug, info, trace},
+    utils::hash_file,
+};
+
+/// zipの圧縮方式. CurseForgeのランチャーで読ませるならdeflated/storedにしておく.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Stored,
+    #[default]
+    Deflated,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    fn method(&self) -> zip::CompressionMethod {
+        match self {
+            Compression::Stored => zip::CompressionMethod::Stored,
+            Compression::Deflated => zip::CompressionMethod::Deflated,
+            Compression::Bzip2 => zip::CompressionMethod::Bzip2,
+            Compression::Zstd => zip::CompressionMethod::Zstd,
+        }
+    }
+}
+
+/// サーバーパックの形式. tarはLinuxのサーバーにそのまま展開する用.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    #[value(name = "zip")]
+    Zip,
+    #[serde(rename = "tar.gz")]
+    #[value(name = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.zst")]
+    #[value(name = "tar.zst")]
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct ArchiveConfig {
+    /// サーバーパックの形式. クライアントはランチャーが読むので常にzip.
+    #[serde(default)]
+    pub server_format: ArchiveFormat,
+    #[serde(default)]
+    pub compression: Compression,
+    /// 圧縮レベル. deflatedなら0-9、zstdなら1-22.
+    /// tarのときはgzipなら0-9、zstdなら1-22に丸めて使う.
+    #[serde(default = "default_level")]
+    pub level: Option<i64>,
+    /// 圧縮済みで縮まないので、そのまま(stored)入れる拡張子.
+    #[serde(default = "default_store_extensions")]
+    pub store_extensions: Vec<String>,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            server_format: ArchiveFormat::default(),
+            compression: Compression::default(),
+            level: default_level(),
+            store_extensions: default_store_extensions(),
+        }
+    }
+}
+
+fn default_level() -> Option<i64> {
+    Some(3)
+}
+
+fn default_store_extensions() -> Vec<String> {
+    ["jar", "zip", "png", "ogg"]
+        .iter()
+        .map(|e| e.to_string())
+        .collect()
+}
+
+impl ArchiveConfig {
+    fn options(&self, path: &Path) -> SimpleFileOptions {
+        let stored = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|ext| {
+                self.store_extensions
+                    .iter()
+                    .any(|s| s.trim_start_matches('.').eq_ignore_ascii_case(ext))
+            });
+        if stored || self.compression == Compression::Stored {
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored)
+        } else {
+            SimpleFileOptions::default()
+                .compression_method(self.compression.method())
+                .compression_level(self.level)
+        }
+    }
+}
+
+// zipで表せる一番古い日時 (1980-01-01T00:00:00Z)
+const ZIP_EPOCH: i64 = 315_532_800;
+
+/// 再現可能なビルドのためのタイムスタンプ(UNIX時間).
+/// `SOURCE_DATE_EPOCH`、gitの最新コミット時刻、zipで表せる一番古い日時の順に使う.
+pub fn source_date_epoch() -> i64 {
+    if let Ok(v) = env::var("SOURCE_DATE_EPOCH")
+        && let Ok(v) = v.trim().parse()
+    {
+        return v;
+    }
+    if let Ok(out) = Command::new("git")
+        .args(["log", "-1", "--format=%ct"])
+        .output()
+        && out.status.success()
+        && let Ok(v) = String::from_utf8_lossy(&out.stdout).trim().parse()
+    {
+        return v;
+    }
+    ZIP_EPOCH
+}
+
+fn zip_datetime(epoch: i64) -> zip::DateTime {
+    let Some(t) = chrono::DateTime::from_timestamp(epoch.max(ZIP_EPOCH), 0) else {
+        return zip::DateTime::default();
+    };
+    // zipの時刻は2秒単位
+    zip::DateTime::from_date_and_time(
+        t.year() as u16,
+        t.month() as u8,
+        t.day() as u8,
+        t.hour() as u8,
+        t.minute() as u8,
+        (t.second() as u8 / 2 * 2).min(58),
+    )
+    .unwrap_or_default()
+}
+
+/// アーカイブに入れるときのパーミッション.
+/// 実行権限の付いたファイルとシェルスクリプトだけ0o755にする.
+pub fn file_mode(path: &Path) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(meta) = fs::metadata(path)
+            && meta.permissions().mode() & 0o111 != 0
+        {
+            return 0o755;
+        }
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("sh") => 0o755,
+        _ => 0o644,
+    }
+}
+
+/// アーカイブ内のパス. OSに関係なく`/`区切りにする.
+fn entry_name(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `<archive>.sha256`を`sha256sum`と同じ形式で書く.
+pub fn write_checksum(archive_name: &Path) -> Result<PathBuf> {
+    let hashes = hash_file(archive_name)?;
+    let file_name = archive_name
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let checksum_path = PathBuf::from(format!("{}.sha256", archive_name.to_string_lossy()));
+    fs::write(
+        &checksum_path,
+        format!("{}  {}\n", hashes.sha256, file_name),
+    )?;
+    info(format!("{}  {}", hashes.sha256, file_name));
+    Ok(checksum_path)
+}
+
+/// `format`に合わせて`directory_path`をアーカイブする.
+pub fn pack_archive(
+    directory_path: &Path,
+    archive_name: &Path,
+    format: ArchiveFormat,
+    config: &ArchiveConfig,
+) -> Result<()> {
+    match format {
+        ArchiveFormat::Zip => directory_archive(directory_path, archive_name, config),
+        _ => tar_archive(directory_path, archive_name, format, config),
+    }
+}
+
+// 名前順に並べたアーカイブのエントリ. パスと`/`区切りの名前の組.
+fn sorted_entries(directory_path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(directory_path).sort_by_file_name() {
+        let entry = entry?;
+        let name = entry_name(entry.path().strip_prefix(directory_path)?);
+        if !name.is_empty() {
+            entries.push((entry.into_path(), name));
+        }
+    }
+    Ok(entries)
+}
+
+// 一度に圧縮しておくファイル数. 圧縮後のデータはこの分だけメモリに載る.
+const CHUNK_FILES: usize = 64;
+
+/// 同じ入力からは同じバイト列のzipができるようにする.
+/// エントリは名前順、時刻は`source_date_epoch`で固定する.
+/// ファイルは並列に圧縮してから、名前順にそのままの圧縮データで書き込む.
+pub fn directory_archive(
+    directory_path: &Path,
+    archive_name: &Path,
+    config: &ArchiveConfig,
+) -> Result<()> {
+    let file = File::create(archive_name)
+        .with_context(|| format!("failed to create {}", archive_name.to_string_lossy()))?;
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    let mtime = zip_datetime(source_date_epoch());
+
+    let entries = sorted_entries(directory_path)?;
+
+    for chunk in entries.chunks(CHUNK_FILES) {
+        let compressed = chunk
+            .par_iter()
+            .map(|(path, name)| {
+                if !path.is_file() {
+                    return Ok(None);
+                }
+                let opts = config
+                    .options(path)
+                    .last_modified_time(mtime)
+                    .unix_permissions(file_mode(path));
+                compress_entry(path, name, opts).map(Some)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for ((path, name), data) in chunk.iter().zip(compressed) {
+            match data {
+                Some(data) => {
+                    debug(format!("adding file {:?} as {:?}", path, name));
+                    let mut single = ZipArchive::new(Cursor::new(data))?;
+                    zip.raw_copy_file(single.by_index(0)?)?;
+                }
+                None => {
+                    trace(format!("create dir {:?}", name));
+                    zip.add_directory(
+                        name,
+                        SimpleFileOptions::default()
+                            .last_modified_time(mtime)
+                            .unix_permissions(0o755),
+                    )?;
+                }
+            }
+        }
+    }
+
+    zip.finish()?;
+    info(format!(
+        "Created Archive {:?}",
+        archive_name.to_string_lossy()
+    ));
+    write_checksum(archive_name)?;
+    Ok(())
+}
+
+// 1ファイルだけのzipをメモリ上に作る. 読み込みはストリームでやる.
+fn compress_entry(path: &Path, name: &str, opts: SimpleFileOptions) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("failed to open {}", path.to_string_lossy()))?,
+    );
+    let large = fs::metadata(path)?.len() >= u32::MAX as u64;
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file(name, opts.large_file(large))?;
+    io::copy(&mut reader, &mut zip)
+        .with_context(|| format!("failed to compress {}", path.to_string_lossy()))?;
+    Ok(zip.finish()?.into_inner())
+}
+
+/// tar.gz/tar.zstで書き出す. パーミッションは`file_mode`で実行権限を残す.
+/// zipと同じく名前順で、時刻と所有者は固定する.
+pub fn tar_archive(
+    directory_path: &Path,
+    archive_name: &Path,
+    format: ArchiveFormat,
+    config: &ArchiveConfig,
+) -> Result<()> {
+    let file = File::create(archive_name)
+        .with_context(|| format!("failed to create {}", archive_name.to_string_lossy()))?;
+    let writer = BufWriter::new(file);
+    match format {
+        ArchiveFormat::TarGz => {
+            let level = config.level.unwrap_or(6).clamp(0, 9) as u32;
+            let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::new(level));
+            write_tar(directory_path, encoder)?.finish()?.flush()?;
+        }
+        ArchiveFormat::TarZst => {
+            let level = config.level.unwrap_or(3).clamp(1, 22) as i32;
+            let encoder = zstd::Encoder::new(writer, level)?;
+            write_tar(directory_path, encoder)?.finish()?.flush()?;
+        }
+        ArchiveFormat::Zip => unreachable!(),
+    }
+    info(format!(
+        "Created Archive {:?}",
+        archive_name.to_string_lossy()
+    ));
+    write_checksum(archive_name)?;
+    Ok(())
+}
+
+fn write_tar<W: Write>(directory_path: &Path, writer: W) -> Result<W> {
+    let mtime = source_date_epoch().max(0) as u64;
+    let mut tar = tar::Builder::new(writer);
+    for (path, name) in sorted_entries(directory_path)? {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        if path.is_file() {
+            debug(format!("adding file {:?} as {:?}", path, name));
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(file_mode(&path));
+            header.set_size(fs::metadata(&path)?.len());
+            let reader = BufReader::new(File::open(&path)?);
+            tar.append_data(&mut header, &name, reader)
+                .with_context(|| format!("failed to add {}", path.to_string_lossy()))?;
+        } else {
+            trace(format!("create dir {:?}", name));
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            tar.append_data(&mut header, format!("{}/", name), io::empty())?;
+        }
+    }
+    Ok(tar.into_inner()?)
+}
@@ -0,0 +1,400 @@
+use std::{
+    fs::{self},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::Result;
+
+use crate::{
+    archive::{directory_archive, pack_archive},
+    cache::Cache,
+    config::{Config, ManifestJson, Mod},
+    curseforge::{fetchmods, resolve_mods},
+    filter::{PathFilter, Side},
+    fingerprint::{self, Fingerprint},
+    lockfile::LockFile,
+    logger::{Fields, debug, info, info_with, set_stage},
+    modlist::write_modlist,
+    modloader::{VersionSet, fetch_modloader},
+    report::PackReport,
+    template::Templater,
+    utils::{copy_dir, copy_file, pack_relative, reset_dir},
+    validate::validate_client_zip,
+};
+
+/// ビルド対象1つ分. プロファイルを適用済みの設定とmanifestを持つ.
+pub struct Target {
+    pub profile: Option<String>,
+    pub config: Config,
+    pub manifest: ManifestJson,
+}
+
+impl Target {
+    /// ビルド対象を列挙する.
+    /// プロファイル指定があればそれだけ、無ければ定義済みの全プロファイルをビルドする.
+    /// プロファイルが1つも無いときは従来通りベースの設定でビルドする.
+    pub fn list(
+        config: &Config,
+        manifest: &ManifestJson,
+        profile: Option<&str>,
+    ) -> Result<Vec<Target>> {
+        let profiles: Vec<String> = match profile {
+            Some(profile) => vec![profile.to_string()],
+            None => config.profiles.keys().cloned().collect(),
+        };
+        if profiles.is_empty() {
+            return Ok(vec![Target {
+                profile: None,
+                config: config.clone(),
+                manifest: manifest.clone(),
+            }]);
+        }
+        profiles
+            .into_iter()
+            .map(|profile| {
+                let profile_config = config.with_profile(&profile)?;
+                let mut profile_manifest = manifest.clone();
+                profile_manifest.apply_profile(&config.profiles[&profile]);
+                Ok(Target {
+                    profile: Some(profile),
+                    config: profile_config,
+                    manifest: profile_manifest,
+                })
+            })
+            .collect()
+    }
+
+    /// 全ビルド対象で使うMOD (重複あり)
+    pub fn used_mods(targets: &[Target]) -> Vec<Mod> {
+        targets
+            .iter()
+            .flat_map(|t| t.manifest.files.clone().unwrap_or_default())
+            .collect()
+    }
+}
+
+/// プロファイル1つ分(プロファイル未定義ならベース)のパックをビルドする.
+/// 解決したMOD/ModLoaderは`lock`に書き戻す.
+/// 入力が前回と同じステージは飛ばす. `force`なら全部やり直す.
+pub fn build_pack(
+    target: Target,
+    outputfolder: &Path,
+    lock: &mut LockFile,
+    cache: &Cache,
+    force: bool,
+) -> Result<PackReport> {
+    let Target {
+        profile,
+        config,
+        manifest,
+    } = target;
+    let profile = profile.as_deref();
+    info(format!("Build profile: {}", profile.unwrap_or("(base)")));
+    let noneed_with_server = config.server_banned_mods();
+
+    // CurseForgeからサーバーパック用のmodを取得
+    // Server ID BANからクライアント系MODは除外済み
+    let started = Instant::now();
+    set_stage(stage_key("resolve", profile));
+    let mut resolved = match &manifest.files {
+        Some(files) => resolve_mods(files, lock, cache)?,
+        None => Vec::new(),
+    };
+    set_stage(stage_key("download", profile));
+    let mod_files = fetchmods(&mut resolved, outputfolder, &noneed_with_server, cache)?;
+    lock.merge_mods(&resolved);
+
+    info_with(
+        "get curseforge mods is end!",
+        Fields::duration(started.elapsed()),
+    );
+
+    let versionset = VersionSet::from_manifest(&manifest)?;
+    let loader_save_path = outputfolder.join("./loader");
+    fs::create_dir_all(&loader_save_path)?;
+
+    // プロファイルごとにzip名とステージングを分けて、互いのファイルが混ざらないようにする
+    let pack_name = match profile {
+        Some(profile) => format!("{}-v{}-{}", config.info.name, config.info.version, profile),
+        None => format!("{}-v{}", config.info.name, config.info.version),
+    };
+    let pack_path = outputfolder.join("./exported");
+    fs::create_dir_all(&pack_path)?;
+
+    set_stage(stage_key("loader", profile));
+    if let Some(loader) =
+        fetch_modloader(&versionset, &loader_save_path, lock.loader.as_ref(), cache)
+    {
+        lock.loader = Some(loader);
+    } else if !versionset.loader_type.has_installer() {
+        // 前のローダーのインストーラーをロックやダウンロード先に残さない
+        lock.loader = None;
+    }
+    let templater = Templater::new(&config, &manifest, profile)?;
+    let mut report = PackReport::new(
+        profile,
+        &config,
+        &manifest,
+        lock.loader.as_ref(),
+        &resolved,
+        &mod_files,
+    );
+    let server_format = config.archive.server_format;
+    let server_archive = pack_path.join(format!(
+        "{}-server.{}",
+        pack_name,
+        server_format.extension()
+    ));
+    let client_archive = pack_path.join(format!("{}-client.zip", pack_name));
+
+    // 両ステージに共通の入力
+    let common_inputs = |stage: &str| -> Result<Fingerprint> {
+        let mut fp = Fingerprint::new(stage);
+        fp.add("config", format!("{:?}", config));
+        fp.add("manifest", serde_json::to_string(&manifest)?);
+        fp.add("mods", serde_json::to_string(&resolved)?);
+        for dir in &config.override_dirs {
+            fp.add_path(Path::new(dir))?;
+        }
+        Ok(fp)
+    };
+
+    '_build_server_package: {
+        let archive_name = &server_archive;
+        let stage = stage_key("server", profile);
+        set_stage(&stage);
+        let started = Instant::now();
+        let mut fp = common_inputs(&stage)?;
+        fp.add_set(
+            "jar",
+            mod_files.iter().map(|f| f.to_string_lossy().into_owned()),
+        );
+        for dir in &config.server_overrides {
+            fp.add_path(Path::new(dir))?;
+        }
+        let fp = fp.finish();
+        if !force && fingerprint::is_fresh(outputfolder, &stage, &fp, &[archive_name]) {
+            break '_build_server_package;
+        }
+        fingerprint::invalidate(outputfolder, &stage)?;
+
+        // 前回のビルドで消したファイルが残らないように毎回空から作る
+        let server_pack_path = staging_dir(outputfolder, "server", profile);
+        reset_dir(&server_pack_path)?;
+        let server_mods_path = server_pack_path.join("./mods");
+        fs::create_dir_all(&server_mods_path)?;
+        for mod_file in &mod_files {
+            if let Some(file_name) = mod_file.file_name() {
+                fs::copy(mod_file, server_mods_path.join(file_name))?;
+            }
+        }
+        stage_overrides(&config, Side::Server, &server_pack_path, &templater)?;
+        info(archive_name.to_string_lossy());
+        pack_archive(
+            &server_pack_path,
+            archive_name,
+            server_format,
+            &config.archive,
+        )?;
+        fingerprint::save(outputfolder, &stage, &fp)?;
+        stage_done(&stage, started);
+    };
+    '_build_Client_package: {
+        // CurseForgeのインポート形式に合わせる
+        // |manifest.json
+        // |modlist.html
+        // |<manifest.overrides>/config, kubejs, 追加ファイル...
+        let archive_name = &client_archive;
+        let stage = stage_key("client", profile);
+        set_stage(&stage);
+        let started = Instant::now();
+        let mut fp = common_inputs(&stage)?;
+        for dir in &config.client_overrides {
+            fp.add_path(Path::new(dir))?;
+        }
+        for f in &config.additional_copy_files {
+            fp.add_path(Path::new(f))?;
+        }
+        for format in &config.modlist_formats {
+            fp.add_path(Path::new(format.file_name()))?;
+        }
+        let fp = fp.finish();
+        if !force && fingerprint::is_fresh(outputfolder, &stage, &fp, &[archive_name]) {
+            break '_build_Client_package;
+        }
+        fingerprint::invalidate(outputfolder, &stage)?;
+
+        let client_pack_path = staging_dir(outputfolder, "client", profile);
+        reset_dir(&client_pack_path)?;
+        let client_overrides_path = client_pack_path.join(manifest.overrides());
+        fs::create_dir_all(&client_overrides_path)?;
+
+        stage_overrides(&config, Side::Client, &client_overrides_path, &templater)?;
+        for f in &config.additional_copy_files {
+            debug(format!("[additional] copy {}", f));
+            copy_file(Path::new(f), &client_overrides_path.join(pack_relative(f)?))?;
+        }
+
+        let mut manifest_json = manifest;
+        manifest_json.name = config.info.name;
+        manifest_json.version = config.info.version;
+        manifest_json.author = config.info.author;
+
+        let manifest_path = client_pack_path.join("./manifest.json");
+        let json_str = serde_json::to_string_pretty(&manifest_json)?;
+        std::fs::write(&manifest_path, json_str)?;
+        write_modlist(&resolved, &config.modlist_formats, &client_pack_path)?;
+
+        info(archive_name.to_string_lossy());
+        directory_archive(&client_pack_path, archive_name, &config.archive)?;
+        validate_client_zip(archive_name)?;
+        fingerprint::save(outputfolder, &stage, &fp)?;
+        stage_done(&stage, started);
+    }
+    report.add_artifact("server", &server_archive, outputfolder)?;
+    report.add_artifact("client", &client_archive, outputfolder)?;
+    Ok(report)
+}
+
+/// override系のディレクトリを`dest`(パックのルート)に重ねてコピーする. 後のレイヤーほど優先される.
+/// 1. override_dirs: 共通. `./config`なら`config/`としてそのまま置く.
+/// 2. server_overrides/client_overrides: 片方のパックだけ. 中身をパックのルートに重ねる.
+///
+/// 各リストの中では後に書いたディレクトリが優先される.
+fn stage_overrides(config: &Config, side: Side, dest: &Path, templater: &Templater) -> Result<()> {
+    for dir in &config.override_dirs {
+        copy_dir(
+            Path::new(dir),
+            &dest.join(pack_relative(dir)?),
+            &PathFilter::new(config, dir, side)?,
+            templater,
+            &format!("common {}", dir),
+        )?;
+    }
+    let side_dirs = match side {
+        Side::Server => &config.server_overrides,
+        Side::Client => &config.client_overrides,
+    };
+    for dir in side_dirs {
+        copy_dir(
+            Path::new(dir),
+            dest,
+            &PathFilter::new(config, dir, side)?,
+            templater,
+            &format!("{} {}", side.name(), dir),
+        )?;
+    }
+    Ok(())
+}
+
+/// オフラインビルドに足りないものを列挙する. 空なら全部手元にある.
+/// ネットワーク待ちでタイムアウトする前に、ビルド開始時点でまとめて報告するために使う.
+pub fn check_offline(
+    targets: &[Target],
+    lock: &LockFile,
+    cache: &Cache,
+    outputfolder: &Path,
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    for target in targets {
+        let banned = target.config.server_banned_mods();
+        for m in target.manifest.files.iter().flatten() {
+            if banned.contains(&m.project_id) {
+                continue;
+            }
+            let issue = match lock.find_mod(m).or_else(|| cache.find_mod(m)) {
+                None => format!(
+                    "projectID={} fileID={} is not in lock file or vendor index",
+                    m.project_id, m.file_id
+                ),
+                Some(l) if !cache.contains(&outputfolder.join("mods").join(&l.file_name)) => {
+                    format!(
+                        "{} (projectID={} fileID={}) is not in cache",
+                        l.file_name, m.project_id, m.file_id
+                    )
+                }
+                Some(_) => continue,
+            };
+            if !missing.contains(&issue) {
+                missing.push(issue);
+            }
+        }
+
+        let loader = match VersionSet::from_manifest(&target.manifest) {
+            Ok(vs) if !vs.loader_type.has_installer() => continue,
+            Ok(vs) => vs.locked_entry(lock.loader.as_ref(), cache),
+            Err(e) => {
+                missing.push(e.to_string());
+                continue;
+            }
+        };
+        if !cache.contains(&outputfolder.join("loader").join(&loader.file_name)) {
+            let issue = format!("{} ({}) is not in cache", loader.file_name, loader.id);
+            if !missing.contains(&issue) {
+                missing.push(issue);
+            }
+        }
+    }
+    missing
+}
+
+/// ロックファイルから参照されなくなったjarをダウンロード先から消す.
+pub fn prune_downloads(outputfolder: &Path, lock: &LockFile) -> Result<()> {
+    let mut keep: Vec<&str> = lock.mods.iter().map(|m| m.file_name.as_str()).collect();
+    if let Some(loader) = &lock.loader {
+        keep.push(&loader.file_name);
+    }
+    for dir in ["mods", "loader"] {
+        let dir = outputfolder.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if entry.file_type()?.is_file() && !keep.contains(&&*name.to_string_lossy()) {
+                info(format!("Remove stale {}", entry.path().to_string_lossy()));
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// ステージングディレクトリ(`.`で始まるもの)を全部消す. ダウンロード済みのjarは残す.
+pub fn clean(outputfolder: &Path) -> Result<()> {
+    if !outputfolder.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(outputfolder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && entry.file_name().to_string_lossy().starts_with('.') {
+            info(format!("Remove {}", entry.path().to_string_lossy()));
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn stage_done(stage: &str, started: Instant) {
+    let elapsed = started.elapsed();
+    info_with(
+        format!("[{}] done in {:.1}s", stage, elapsed.as_secs_f64()),
+        Fields::duration(elapsed),
+    );
+}
+
+/// ステージ名. `server`/`server-lite`のようにする.
+/// フィンガープリントの記録名やJSONログの`stage`に使う.
+fn stage_key(kind: &str, profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{}-{}", kind, profile),
+        None => kind.to_string(),
+    }
+}
+
+/// ステージングディレクトリ. ベースは従来通り`.server`/`.client`を使う.
+fn staging_dir(outputfolder: &Path, kind: &str, profile: Option<&str>) -> PathBuf {
+    outputfolder.join(format!(".{}", stage_key(kind, profile)))
+}
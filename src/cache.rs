@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use reqwest::blocking::Client;
+use walkdir::WalkDir;
+
+use crate::{
+    config::Mod,
+    lockfile::{LockedLoader, LockedMod},
+    logger::{debug, warn},
+    utils::{DownloadError, fetch_file},
+    vendor::VendorIndex,
+};
+
+/// ダウンロード済みのjarを探すためのキャッシュ.
+/// `cache_dirs`以下を再帰的に見て、ファイル名で引けるようにしておく.
+/// vendorしたディレクトリなら目録からMODの解決もできる.
+pub struct Cache {
+    files: HashMap<String, PathBuf>,
+    mods: Vec<LockedMod>,
+    loaders: Vec<LockedLoader>,
+    offline: bool,
+}
+
+impl Cache {
+    pub fn new(dirs: &[String], offline: bool) -> Cache {
+        let mut files = HashMap::new();
+        let mut mods = Vec::new();
+        let mut loaders = Vec::new();
+        for dir in dirs {
+            match VendorIndex::read(Path::new(dir)) {
+                Ok(Some(index)) => {
+                    mods.extend(index.mods);
+                    loaders.extend(index.loaders);
+                }
+                Ok(None) => {}
+                Err(e) => warn(format!("{:#}", e)),
+            }
+            for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    // 先に書いたディレクトリを優先する
+                    files
+                        .entry(name)
+                        .or_insert_with(|| entry.path().to_path_buf());
+                }
+            }
+        }
+        Cache {
+            files,
+            mods,
+            loaders,
+            offline,
+        }
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    pub fn find(&self, file_name: &str) -> Option<&PathBuf> {
+        self.files.get(file_name)
+    }
+
+    pub fn find_mod(&self, m: &Mod) -> Option<&LockedMod> {
+        self.mods
+            .iter()
+            .find(|l| l.project_id == m.project_id && l.file_id == m.file_id)
+    }
+
+    pub fn find_loader(&self, id: &str, minecraft: &str) -> Option<&LockedLoader> {
+        self.loaders
+            .iter()
+            .find(|l| l.id == id && l.minecraft == minecraft)
+    }
+
+    /// `save_path`が無ければキャッシュからコピーし、それでも無ければダウンロードする.
+    /// オフラインのときはネットワークには一切出ない.
+    pub fn fetch(
+        &self,
+        client: &Client,
+        download_url: &String,
+        save_path: &Path,
+    ) -> Result<(), DownloadError> {
+        if !save_path.exists() {
+            let file_name = save_path.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(cached) = self.find(&file_name) {
+                debug(format!("Copy {:?} from cache {:?}", save_path, cached));
+                return fs::copy(cached, save_path)
+                    .map(|_| ())
+                    .map_err(|_| DownloadError::CopyFault);
+            }
+            if self.offline {
+                return Err(DownloadError::Offline);
+            }
+        }
+        fetch_file(client, download_url, &save_path.to_path_buf())
+    }
+
+    /// ダウンロード先かキャッシュにファイルがあるか.
+    pub fn contains(&self, save_path: &Path) -> bool {
+        save_path.exists()
+            || save_path
+                .file_name()
+                .is_some_and(|name| self.find(&name.to_string_lossy()).is_some())
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    slice,
+};
+
+use anyhow::{Context, Result, bail};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde_derive::Serialize;
+use sha1::{Digest, Sha1};
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+use crate::{
+    cache::Cache,
+    config::{Config, ManifestJson, Mod},
+    curseforge::resolve_mods,
+    filter::{PathFilter, Side},
+    lockfile::{LockFile, LockedMod},
+    logger::{info, warn},
+    template::Templater,
+    utils::pack_relative,
+};
+
+/// 比較するパックの状態.
+/// 設定ファイルはoverrideからの相対パスとgitのblobハッシュで持つ.
+/// ただのmanifest.jsonを渡されたときは設定ファイルの情報は無い.
+struct Snapshot {
+    label: String,
+    manifest: ManifestJson,
+    files: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize)]
+struct ModChange {
+    #[serde(rename = "projectID")]
+    project_id: u32,
+    name: String,
+    #[serde(rename = "oldFileID")]
+    old_file_id: Option<u32>,
+    #[serde(rename = "newFileID")]
+    new_file_id: Option<u32>,
+    #[serde(rename = "oldFile")]
+    old_file: Option<String>,
+    #[serde(rename = "newFile")]
+    new_file: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct FileChanges {
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Changelog {
+    from: String,
+    to: String,
+    added: Vec<ModChange>,
+    removed: Vec<ModChange>,
+    updated: Vec<ModChange>,
+    downgraded: Vec<ModChange>,
+    // どちらかに設定ファイルの情報が無ければnull
+    files: Option<FileChanges>,
+}
+
+/// 2つのパックの差分を`out_dir`に`changelog.md`と`changelog.json`として書き出す.
+/// `from`/`to`はmanifest.json、書き出したクライアントzip、gitのrefのどれか.
+/// `to`を省略すると今の作業ディレクトリと比べる.
+pub fn changelog(
+    config: &Config,
+    lock: &LockFile,
+    cache: &Cache,
+    from: &str,
+    to: Option<&str>,
+    out_dir: &Path,
+) -> Result<()> {
+    let old = load_snapshot(from, config)?;
+    let new = match to {
+        Some(to) => load_snapshot(to, config)?,
+        None => current_snapshot(config)?,
+    };
+    info(format!("Compare {} -> {}", old.label, new.label));
+
+    let old_mods = old.manifest.files.clone().unwrap_or_default();
+    let new_mods = new.manifest.files.clone().unwrap_or_default();
+    let mut all_mods: Vec<Mod> = Vec::new();
+    for m in old_mods.iter().chain(&new_mods) {
+        if !all_mods.contains(m) {
+            all_mods.push(m.clone());
+        }
+    }
+    // 古いrefには消されたファイルやロックファイルに無いMODもあるので、
+    // 解決できないものはfileIDだけ載せて、changelog自体は書き出す
+    let resolved: Vec<LockedMod> = all_mods
+        .par_iter()
+        .filter_map(|m| match resolve_mods(slice::from_ref(m), lock, cache) {
+            Ok(r) => r.into_iter().next(),
+            Err(e) => {
+                // エラーにprojectID/fileIDは入っている
+                warn(format!("{:#}, listed by fileID only", e));
+                None
+            }
+        })
+        .collect();
+    let find = |m: &Mod| {
+        resolved
+            .iter()
+            .find(|r| r.project_id == m.project_id && r.file_id == m.file_id)
+    };
+    let change = |old: Option<&Mod>, new: Option<&Mod>| {
+        let old_r = old.and_then(find);
+        let new_r = new.and_then(find);
+        let project_id = old.or(new).map(|m| m.project_id).unwrap_or_default();
+        ModChange {
+            project_id,
+            name: new_r
+                .or(old_r)
+                .map(display_name)
+                .unwrap_or_else(|| format!("projectID {}", project_id)),
+            old_file_id: old.map(|m| m.file_id),
+            new_file_id: new.map(|m| m.file_id),
+            old_file: old_r.map(|r| r.file_name.clone()),
+            new_file: new_r.map(|r| r.file_name.clone()),
+        }
+    };
+
+    let mut log = Changelog {
+        from: old.label,
+        to: new.label,
+        added: Vec::new(),
+        removed: Vec::new(),
+        updated: Vec::new(),
+        downgraded: Vec::new(),
+        files: None,
+    };
+    for n in &new_mods {
+        match old_mods.iter().find(|o| o.project_id == n.project_id) {
+            None => log.added.push(change(None, Some(n))),
+            // fileIDは後からアップロードされたものほど大きい
+            Some(o) if o.file_id < n.file_id => log.updated.push(change(Some(o), Some(n))),
+            Some(o) if o.file_id > n.file_id => log.downgraded.push(change(Some(o), Some(n))),
+            Some(_) => {}
+        }
+    }
+    for o in &old_mods {
+        if !new_mods.iter().any(|n| n.project_id == o.project_id) {
+            log.removed.push(change(Some(o), None));
+        }
+    }
+    for list in [
+        &mut log.added,
+        &mut log.removed,
+        &mut log.updated,
+        &mut log.downgraded,
+    ] {
+        list.sort_by_key(|c| c.name.to_lowercase());
+    }
+
+    if let (Some(old_files), Some(new_files)) = (&old.files, &new.files) {
+        let mut files = FileChanges::default();
+        for (path, hash) in new_files {
+            match old_files.get(path) {
+                None => files.added.push(path.clone()),
+                Some(old_hash) if old_hash != hash => files.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        for path in old_files.keys() {
+            if !new_files.contains_key(path) {
+                files.removed.push(path.clone());
+            }
+        }
+        log.files = Some(files);
+    }
+
+    fs::create_dir_all(out_dir)?;
+    let md_path = out_dir.join("changelog.md");
+    let json_path = out_dir.join("changelog.json");
+    fs::write(&md_path, markdown(&log))?;
+    fs::write(&json_path, serde_json::to_string_pretty(&log)?)?;
+    info(format!(
+        "Changelog written to {} and {}",
+        md_path.to_string_lossy(),
+        json_path.to_string_lossy()
+    ));
+    Ok(())
+}
+
+fn display_name(m: &LockedMod) -> String {
+    m.name.clone().unwrap_or_else(|| m.file_name.clone())
+}
+
+fn markdown(log: &Changelog) -> String {
+    let mut out = format!("# Changelog: {} -> {}\n", log.from, log.to);
+    let mods = |out: &mut String, title: &str, list: &[ModChange]| {
+        if list.is_empty() {
+            return;
+        }
+        *out += &format!("\n## {}\n\n", title);
+        for c in list {
+            let file = |f: &Option<String>, id: Option<u32>| {
+                f.clone()
+                    .or(id.map(|id| format!("fileID {}", id)))
+                    .unwrap_or_default()
+            };
+            *out += &match (c.old_file_id, c.new_file_id) {
+                (Some(_), Some(_)) => format!(
+                    "- {}: {} -> {}\n",
+                    c.name,
+                    file(&c.old_file, c.old_file_id),
+                    file(&c.new_file, c.new_file_id)
+                ),
+                (None, Some(_)) => format!("- {} ({})\n", c.name, file(&c.new_file, c.new_file_id)),
+                _ => format!("- {} ({})\n", c.name, file(&c.old_file, c.old_file_id)),
+            };
+        }
+    };
+    mods(&mut out, "Added", &log.added);
+    mods(&mut out, "Updated", &log.updated);
+    mods(&mut out, "Downgraded", &log.downgraded);
+    mods(&mut out, "Removed", &log.removed);
+
+    if let Some(files) = &log.files
+        && !(files.added.is_empty() && files.removed.is_empty() && files.modified.is_empty())
+    {
+        out += "\n## Config changes\n\n";
+        for (mark, list) in [
+            ("added", &files.added),
+            ("modified", &files.modified),
+            ("removed", &files.removed),
+        ] {
+            for path in list {
+                out += &format!("- {}: `{}`\n", mark, path);
+            }
+        }
+    }
+    out
+}
+
+fn load_snapshot(spec: &str, config: &Config) -> Result<Snapshot> {
+    let path = Path::new(spec);
+    if path.is_file() && spec.ends_with(".zip") {
+        return zip_snapshot(path);
+    }
+    if path.is_file() {
+        let raw_data = fs::read_to_string(path)?;
+        let manifest =
+            serde_json::from_str(&raw_data).with_context(|| format!("failed to parse {}", spec))?;
+        return Ok(Snapshot {
+            label: spec.to_string(),
+            manifest,
+            files: None,
+        });
+    }
+    git_snapshot(spec, config)
+}
+
+// 作業ディレクトリの今の状態
+fn current_snapshot(config: &Config) -> Result<Snapshot> {
+    let raw_data = fs::read_to_string(&config.manifest)?;
+    let manifest = serde_json::from_str(&raw_data).context("failed to parse manifest json")?;
+    let files = pack_files(Path::new(""), config, &manifest)?;
+    Ok(Snapshot {
+        label: "working tree".to_string(),
+        manifest,
+        files: Some(files),
+    })
+}
+
+// CurseForge形式のクライアントzip. overridesの中身はビルド済みなのでそのまま見る
+fn zip_snapshot(path: &Path) -> Result<Snapshot> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let manifest: ManifestJson = {
+        let mut raw_data = String::new();
+        archive
+            .by_name("manifest.json")
+            .context("manifest.json is not in the zip")?
+            .read_to_string(&mut raw_data)?;
+        serde_json::from_str(&raw_data).context("failed to parse manifest json")?
+    };
+    let prefix = format!("{}/", manifest.overrides());
+    let mut files = BTreeMap::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let Some(rel) = entry.name().strip_prefix(&prefix).map(normalize) else {
+            continue;
+        };
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(rel, git_blob_hash(&data));
+    }
+    Ok(Snapshot {
+        label: path.to_string_lossy().to_string(),
+        manifest,
+        files: Some(files),
+    })
+}
+
+// gitのrefの時点の状態
+// 一時ディレクトリに展開して、作業ディレクトリと同じようにパックの中身を組み立てる
+fn git_snapshot(rev: &str, config: &Config) -> Result<Snapshot> {
+    let raw_data = git(&["show", &format!("{}:{}", rev, git_path(&config.manifest))])
+        .with_context(|| format!("`{}` is not a file, zip or git ref", rev))?;
+    let manifest = serde_json::from_str(&raw_data).context("failed to parse manifest json")?;
+
+    // サブディレクトリで`git archive`するとそこで絞られてしまうので、
+    // カレントディレクトリのツリーをIDにしてからリポジトリのルートで展開する
+    let tree = git(&["rev-parse", &format!("{}:./", rev)])?;
+    let top = git(&["rev-parse", "--show-toplevel"])?;
+    let output = Command::new("git")
+        .args(["-C", top.trim(), "archive", "--format=tar", tree.trim()])
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "git archive {} failed: {}",
+            rev,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let root = env::temp_dir().join(format!("mcpack-changelog-{}", process::id()));
+    if root.exists() {
+        fs::remove_dir_all(&root)?;
+    }
+    fs::create_dir_all(&root)?;
+    let files = tar::Archive::new(output.stdout.as_slice())
+        .unpack(&root)
+        .map_err(Into::into)
+        .and_then(|_| {
+            // その時点の設定で組み立てる. 読めなければ今の設定を使う
+            let ref_config = fs::read_to_string(root.join("config.toml"))
+                .ok()
+                .and_then(|raw| toml::from_str::<Config>(&raw).ok());
+            pack_files(&root, ref_config.as_ref().unwrap_or(config), &manifest)
+        });
+    fs::remove_dir_all(&root)?;
+    Ok(Snapshot {
+        label: rev.to_string(),
+        manifest,
+        files: Some(files?),
+    })
+}
+
+/// クライアントパックのoverridesに入る中身. `root`はoverride_dirsなどの基準になるディレクトリ.
+/// ビルドと同じく、override_dirs -> client_overrides -> additional_copy_filesの順に重ねて、
+/// フィルタとテンプレートも通す.
+fn pack_files(
+    root: &Path,
+    config: &Config,
+    manifest: &ManifestJson,
+) -> Result<BTreeMap<String, String>> {
+    let templater = Templater::new(config, manifest, None)?;
+    let mut files = BTreeMap::new();
+    let layers = config
+        .override_dirs
+        .iter()
+        .map(|dir| Ok((dir, pack_relative(dir)?)))
+        .chain(
+            config
+                .client_overrides
+                .iter()
+                .map(|dir| Ok((dir, PathBuf::new()))),
+        )
+        .collect::<Result<Vec<_>>>()?;
+    for (dir, dest) in layers {
+        let filter = PathFilter::new_in(root, config, dir, Side::Client)?;
+        let source = root.join(dir);
+        for entry in WalkDir::new(&source).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let rel = entry.path().strip_prefix(&source)?;
+            if !filter.is_match(rel) {
+                continue;
+            }
+            // テンプレートのパターンはカレントディレクトリからのパスで書かれている
+            let data = if templater.is_match(&Path::new(dir).join(rel)) {
+                templater.render_file(entry.path())?
+            } else {
+                fs::read(entry.path())?
+            };
+            files.insert(
+                normalize(&dest.join(rel).to_string_lossy()),
+                git_blob_hash(&data),
+            );
+        }
+    }
+    for f in &config.additional_copy_files {
+        let path = root.join(f);
+        if path.is_file() {
+            files.insert(
+                normalize(&pack_relative(f)?.to_string_lossy()),
+                git_blob_hash(&fs::read(path)?),
+            );
+        }
+    }
+    Ok(files)
+}
+
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// `git show`はrefの後ろに`./`を付けるとカレントディレクトリからの相対パスになる
+fn git_path(path: &str) -> String {
+    format!("./{}", normalize(path))
+}
+
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/").trim_start_matches("./").to_string()
+}
+
+/// gitと同じblobハッシュ. gitのrefとファイルを同じ基準で比べるために使う.
+fn git_blob_hash(data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("blob {}\0", data.len()).as_bytes());
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
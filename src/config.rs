@@ -1,58 +1,354 @@
-use clap::Parser;
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::{Result, anyhow};
+use clap::{Parser, Subcommand};
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::{
+    archive::{ArchiveConfig, ArchiveFormat},
+    filter::OverrideFilter,
+    logger::{LogConfig, LogFormat},
+    modlist::ModlistFormat,
+    utils::pack_relative,
+};
+
 const DESC: &str = r#"Minecraft Modpack Builder       
 Licenced by: MIT-License (c) 2025 Ponzu-IKA(TsukamattaHiyoko)"#;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = DESC)]
-pub struct Args {}
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// ビルドするプロファイル名. 省略時は定義されている全プロファイルをビルドする.
+    #[arg(short, long, global = true)]
+    pub profile: Option<String>,
+
+    /// ロックファイルとmanifestがずれていたら失敗させる. ロックファイルは更新しない.
+    #[arg(long, global = true)]
+    pub locked: bool,
+
+    /// ネットワークに出ず、ロックファイルとキャッシュだけでビルドする.
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// 入力が前回のビルドと同じでも全ステージをやり直す.
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// ログを細かくする. `-vv`でtraceまで出す.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// ログを静かにする. `-q`でwarn以上、`-qq`でerrorだけ.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// ログの形式. `json`なら1行1オブジェクトで出す. 設定の`log.format`より優先する.
+    #[arg(long, global = true)]
+    pub log_format: Option<LogFormat>,
+
+    /// config.tomlの知らないキーを警告ではなくエラーにする.
+    #[arg(long, global = true)]
+    pub strict: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// パックをビルドする (サブコマンド省略時はこれ)
+    Build {
+        /// サーバーパックの形式. 設定の`archive.server_format`より優先する.
+        #[arg(long)]
+        server_format: Option<ArchiveFormat>,
+    },
+    /// 全MODとModLoaderをダウンロードして、別マシンに持ち込めるディレクトリにまとめる.
+    /// まとめたディレクトリは`cache_dirs`に登録して使う.
+    Vendor {
+        #[arg(default_value = "./vendor")]
+        dir: PathBuf,
+    },
+    /// 2つのパックを比べてパッチノートを作る.
+    /// FROM/TOにはmanifest.json、書き出したクライアントzip、gitのrefを指定できる.
+    #[command(visible_alias = "diff")]
+    Changelog {
+        from: String,
+        /// 省略すると今の作業ディレクトリと比べる
+        to: Option<String>,
+        #[arg(long, default_value = "./distribution")]
+        out_dir: PathBuf,
+    },
+    /// ステージングディレクトリを消す. ダウンロード済みのMODは残す.
+    Clean,
+    /// config.tomlとmanifest.jsonを確認するだけで、ビルドはしない.
+    Check,
+    /// config.tomlのJSON Schemaを書き出す. エディタの補完やチェックに使う.
+    /// config.tomlの先頭に`#:schema ./config.schema.json`と書くとtaploなどが読む.
+    Schema {
+        #[arg(default_value = "./config.schema.json")]
+        out: PathBuf,
+    },
+    /// 新しいパックのconfig.tomlとmanifest.jsonを作る. 省略した項目は聞く.
+    Init {
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        author: Option<String>,
+        /// パックのバージョン
+        #[arg(long = "pack-version")]
+        version: Option<String>,
+        /// Minecraftのバージョン. 例: 1.20.1
+        #[arg(long)]
+        minecraft: Option<String>,
+        /// MODローダー. 例: forge-47.3.0, neoforge-21.1.77, fabric-0.16.5
+        #[arg(long)]
+        loader: Option<String>,
+        /// 既にあるconfig.tomlとmanifest.jsonを上書きする
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// manifest.jsonのMODを追加/削除/更新する.
+    Mod {
+        #[command(subcommand)]
+        command: ModCommand,
+    },
+    /// CurseForgeのエクスポートzipを展開して、config.tomlを作る.
+    Import {
+        zip: PathBuf,
+        /// 既にあるconfig.toml、manifest.json、overrideのファイルを上書きする
+        #[arg(long)]
+        overwrite: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ModCommand {
+    /// MODを追加する. パックのMinecraftバージョンとローダーに合う一番新しいファイルを選ぶ.
+    Add {
+        /// CurseForgeのslugかprojectID
+        project: String,
+        /// fileIDか、ファイル名/表示名に含まれる文字列. 省略時は最新.
+        #[arg(long)]
+        version: Option<String>,
+        /// manifestで`required = false`にする
+        #[arg(long)]
+        optional: bool,
+    },
+    /// MODを削除する.
+    Remove {
+        /// CurseForgeのslugかprojectID
+        #[arg(required = true)]
+        projects: Vec<String>,
+    },
+    /// MODを一番新しいファイルに更新する.
+    Update {
+        /// CurseForgeのslugかprojectID
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        projects: Vec<String>,
+        /// manifestの全MODを更新する
+        #[arg(long)]
+        all: bool,
+    },
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 pub struct Config {
     pub info: Info,
+    /// 知らないキーをエラーにする. `--strict`と同じ.
+    #[serde(default)]
+    pub strict: bool,
     #[serde(default = "default_manifest")]
     pub manifest: String,
+    #[serde(default = "default_lockfile")]
+    pub lockfile: String,
+    /// jarを探しに行くディレクトリ. 手動で落としたMODやvendorしたディレクトリを指定する.
+    #[serde(default)]
+    pub cache_dirs: Vec<String>,
     #[serde(default)]
     pub default_config: DefaultConfig,
 
     #[serde(default = "default_dirs")]
     pub override_dirs: Vec<String>,
 
-    // modlist.htmlのように一切の変更なしにclientパックにぶち込むやつはここで定義
+    /// サーバー/クライアントだけに入れるoverride. 中身をパックのルートに重ねる.
+    /// 例: `./server/defaultconfigs/...`や`./server/server.properties`.
+    /// override_dirsより優先され、同じパスのファイルはこっちで上書きされる.
+    #[serde(default)]
+    pub server_overrides: Vec<String>,
+    #[serde(default)]
+    pub client_overrides: Vec<String>,
+
+    /// `{{pack.name}}`/`{{pack.version}}`/`{{mc.version}}`/`{{loader.version}}`などを置き換えるファイル.
+    /// パターンはカレントディレクトリからのパスで書く. 例: `config/bcc-common.toml`
+    #[serde(default)]
+    pub template_files: Vec<String>,
+
+    /// override_dirsごとのinclude/excludeパターン. キーはoverride_dirsと同じディレクトリを指す.
+    /// パターンはそのディレクトリからの相対パスで書く. 例: `[override_filters."./config".server]`
+    /// 各ディレクトリ直下の`.packignore`も読む.
+    #[serde(default)]
+    pub override_filters: BTreeMap<String, OverrideFilter>,
+
+    // LICENCE.mdのように一切の変更なしにclientパックにぶち込むやつはここで定義
     // 例:  |overrides
-    //      |- modlist.html
     //      |- LICENCE.md
     #[serde(default)]
     pub additional_copy_files: Vec<String>,
-    
+
+    /// クライアントパックに入れるmodlistの形式. html/md/json.
+    /// カレントディレクトリに`modlist.html`などがあれば、生成せずにそっちを使う.
+    #[serde(default = "default_modlist_formats")]
+    pub modlist_formats: Vec<ModlistFormat>,
+
+    /// ログレベル. 画面とログファイルで別に指定できる.
+    #[serde(default)]
+    pub log: LogConfig,
+
+    /// zipの圧縮方式とレベル. `.jar`などの圧縮済みファイルは圧縮せずに入れる.
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+
     /// ProjectIDを指定することでサーバーパックにクライアントMODが入ることを阻止できる.
     /// デフォルトの指定じゃ足りないときに使うよ.
     #[serde(default)]
     pub additional_noneeds_with_server: Vec<u32>,
+
+    /// lite/fullのような派生版をここで定義する.
+    /// 各プロファイルはベースの設定を引き継いで差分だけ上書きする.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
 }
 
-fn default_dirs() -> Vec<String> {
+impl Config {
+    /// サーバーパックに入れないMODのprojectID.
+    pub fn server_banned_mods(&self) -> Vec<u32> {
+        let mut banned = self.default_config.no_needs_with_server.clone();
+        banned.extend(&self.additional_noneeds_with_server);
+        banned
+    }
+
+    /// プロファイルを適用した設定を返す.
+    pub fn with_profile(&self, name: &str) -> Result<Config> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| anyhow!("profile `{}` is not defined in config", name))?;
+        let mut config = self.clone();
+
+        if let Some(name) = &profile.info.name {
+            config.info.name = name.clone();
+        }
+        if let Some(version) = &profile.info.version {
+            config.info.version = version.clone();
+        }
+        if let Some(author) = &profile.info.author {
+            config.info.author = author.clone();
+        }
+        apply_list(
+            &mut config.override_dirs,
+            &profile.add_override_dirs,
+            &profile.remove_override_dirs,
+        );
+        apply_list(
+            &mut config.additional_copy_files,
+            &profile.add_additional_copy_files,
+            &profile.remove_additional_copy_files,
+        );
+        Ok(config)
+    }
+}
+
+fn apply_list(list: &mut Vec<String>, add: &[String], remove: &[String]) {
+    list.retain(|v| !remove.iter().any(|r| same_path(v, r)));
+    for v in add {
+        if !list.iter().any(|l| same_path(l, v)) {
+            list.push(v.clone());
+        }
+    }
+}
+
+/// `./kubejs`と`kubejs`のような書き方の違いを無視して比べる.
+pub fn same_path(a: &str, b: &str) -> bool {
+    match (pack_relative(a), pack_relative(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// ベースの設定との差分.
+/// 何も指定しなければベースと同じ内容になる.
+#[derive(Debug, Deserialize, JsonSchema, Default, Clone)]
+pub struct Profile {
+    #[serde(default)]
+    pub info: InfoOverride,
+
+    /// 追加するMOD. 同じprojectIDがあればfileIDを差し替える.
+    #[serde(default)]
+    pub add_mods: Vec<Mod>,
+    /// 除外するMODのprojectID.
+    #[serde(default)]
+    pub remove_mods: Vec<u32>,
+
+    #[serde(default)]
+    pub add_override_dirs: Vec<String>,
+    #[serde(default)]
+    pub remove_override_dirs: Vec<String>,
+
+    #[serde(default)]
+    pub add_additional_copy_files: Vec<String>,
+    #[serde(default)]
+    pub remove_additional_copy_files: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Default, Clone)]
+pub struct InfoOverride {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub author: Option<String>,
+}
+
+pub fn default_dirs() -> Vec<String> {
     vec!["./config".to_string(), "./kubejs".to_string()]
 }
 
+fn default_modlist_formats() -> Vec<ModlistFormat> {
+    vec![ModlistFormat::Html]
+}
+
 fn default_manifest() -> String {
     "./manifest.json".to_string()
 }
 
-#[derive(Debug, Deserialize)]
+fn default_lockfile() -> String {
+    "./mcpack.lock".to_string()
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 pub struct DefaultConfig {
     pub no_needs_with_server: Vec<u32>,
+    /// override_dirsから常に除外するもの.
+    #[serde(default = "default_override_excludes")]
+    pub override_excludes: Vec<String>,
 }
 
 impl Default for DefaultConfig {
     fn default() -> Self {
         Self {
             no_needs_with_server: default_no_needs_with_server(),
+            override_excludes: default_override_excludes(),
         }
     }
 }
 
+fn default_override_excludes() -> Vec<String> {
+    // エディタのバックアップやVCSのゴミ
+    [".git/", "*~", "*.bak", "*.swp", ".DS_Store", "Thumbs.db"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
 fn default_no_needs_with_server() -> Vec<u32> {
     // サーバーにいらないmod.
     vec![
@@ -64,7 +360,7 @@ fn default_no_needs_with_server() -> Vec<u32> {
 }
 
 // manifest.jsonを上書きするためのinfo
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, JsonSchema, Default, Clone)]
 pub struct Info {
     pub name: String,
     pub version: String,
@@ -72,7 +368,7 @@ pub struct Info {
 }
 
 // manifest.jsonを読み取るためのstruct
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ManifestJson {
     pub author: String,
     pub version: String,
@@ -90,24 +386,68 @@ pub struct ManifestJson {
     overrides: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl ManifestJson {
+    /// `init`用の空のmanifest.
+    pub fn new(info: &Info, minecraft: &str, loader: &str) -> ManifestJson {
+        ManifestJson {
+            author: info.author.clone(),
+            version: info.version.clone(),
+            name: info.name.clone(),
+            files: Some(Vec::new()),
+            minecraft: Minecraft {
+                mod_loaders: vec![ModLoader {
+                    id: loader.to_string(),
+                    primary: true,
+                }],
+                version: minecraft.to_string(),
+            },
+            manifest_type: "minecraftModpack".to_string(),
+            manifest_version: 1,
+            overrides: "overrides".to_string(),
+        }
+    }
+
+    /// zip内のoverrideフォルダ名
+    pub fn overrides(&self) -> &str {
+        &self.overrides
+    }
+
+    /// プロファイルのMOD追加/除外を適用する.
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        let files = self.files.get_or_insert_with(Vec::new);
+        files.retain(|m| !profile.remove_mods.contains(&m.project_id));
+        for add in &profile.add_mods {
+            match files.iter_mut().find(|m| m.project_id == add.project_id) {
+                Some(m) => *m = add.clone(),
+                None => files.push(add.clone()),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Minecraft {
     #[serde(default, rename = "modLoaders")]
     pub mod_loaders: Vec<ModLoader>,
     pub version: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ModLoader {
     pub id: String,
     pub primary: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize, PartialEq, Clone)]
 pub struct Mod {
     #[serde(rename = "fileID")]
     pub file_id: u32,
     #[serde(rename = "projectID")]
     pub project_id: u32,
+    #[serde(default = "default_required")]
     pub required: bool,
 }
+
+fn default_required() -> bool {
+    true
+}
@@ -1,17 +1,22 @@
 use crate::{
+    cache::Cache,
     config::Mod,
-    logger::{error, info, warn},
-    utils::{DownloadError, fetch_file},
+    lockfile::{LockFile, LockedMod},
+    logger::{Fields, debug, error, error_with, info_with, warn, warn_with},
+    modloader::VersionSet,
+    utils::{DownloadError, hash_file},
 };
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use anyhow::anyhow;
+use rayon::iter::{IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator};
 use reqwest::blocking::Client;
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
 use std::{
     fs::{self},
     path::{Path, PathBuf},
     sync::Mutex,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 #[derive(Deserialize, Debug)]
@@ -22,8 +27,68 @@ struct FileResponse {
 #[derive(Deserialize, Debug)]
 #[allow(warnings)]
 struct FileData {
+    id: u32,
     fileName: String,
-    downloadUrl: String,
+    // 配布許可の無いMODはnullになる
+    downloadUrl: Option<String>,
+    #[serde(default)]
+    fileLength: u64,
+    #[serde(default)]
+    hashes: Vec<FileHash>,
+    #[serde(default)]
+    displayName: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct FilesResponse {
+    data: Vec<FileData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponse {
+    data: Vec<SearchData>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(warnings)]
+struct SearchData {
+    id: u32,
+    slug: String,
+    name: String,
+    classId: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModResponse {
+    data: ModData,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(warnings)]
+struct ModData {
+    name: String,
+    #[serde(default)]
+    authors: Vec<Author>,
+    #[serde(default)]
+    links: Links,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[allow(warnings)]
+struct Links {
+    websiteUrl: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Author {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct FileHash {
+    value: String,
+    // 1: sha1, 2: md5
+    algo: u8,
 }
 
 pub fn retry<F, T, E>(mut f: F, retries: usize, delay: Duration) -> Result<T, E>
@@ -43,72 +108,339 @@ where
     f()
 }
 
-fn get_json(client: &Client, url: &String) -> Result<FileResponse, reqwest::Error> {
-    info(url);
-    client.get(url).send().expect("Request Failed").json()
+fn get_json<T: DeserializeOwned>(client: &Client, url: &String) -> Result<T, reqwest::Error> {
+    debug(url);
+    // panicするとretryもされないので、通信エラーもそのまま返す
+    client.get(url).send()?.json()
+}
+
+/// projectID/fileIDからファイル名やURLを解決する.
+/// ロックファイルかvendorの目録にあればAPIは叩かない. オフラインならそれらだけで解決する.
+pub fn resolve_mods(
+    mod_list: &[Mod],
+    lock: &LockFile,
+    cache: &Cache,
+) -> anyhow::Result<Vec<LockedMod>> {
+    let sleep = Duration::from_secs(5); //APIがパンクしちゃうのでちょっと長めに待たせる
+    let client = Client::new();
+
+    mod_list
+        .par_iter()
+        .map(|cf_mod| {
+            // 2つ目はAPIから今回解決したか. ロック済みのものはメタデータAPIも叩かない
+            if let Some(locked) = lock.find_mod(cf_mod).or_else(|| cache.find_mod(cf_mod)) {
+                return Ok((locked.clone(), false));
+            }
+            if cache.is_offline() {
+                return Err(anyhow!(
+                    "projectID={} fileID={} is not in lock file or vendor index (offline)",
+                    cf_mod.project_id,
+                    cf_mod.file_id
+                ));
+            }
+            let url = format!(
+                "https://api.curse.tools/v1/cf/mods/{}/files/{}",
+                cf_mod.project_id, cf_mod.file_id
+            );
+            let response: FileResponse =
+                retry(|| get_json(&client, &url), 5, sleep).map_err(|e| {
+                    let msg = format!(
+                        "failed to resolve projectID={} fileID={}: {}",
+                        cf_mod.project_id, cf_mod.file_id, e
+                    );
+                    error_with(
+                        &msg,
+                        Fields {
+                            project_id: Some(cf_mod.project_id),
+                            file_id: Some(cf_mod.file_id),
+                            ..Default::default()
+                        },
+                    );
+                    anyhow!(msg)
+                })?;
+            let data = response.data;
+            let hash = |algo: u8| {
+                data.hashes
+                    .iter()
+                    .find(|h| h.algo == algo)
+                    .map(|h| h.value.clone())
+            };
+
+            Ok((
+                LockedMod {
+                    project_id: cf_mod.project_id,
+                    file_id: cf_mod.file_id,
+                    download_url: data
+                        .downloadUrl
+                        .clone()
+                        .unwrap_or_else(|| cdn_url(data.id, &data.fileName)),
+                    size: data.fileLength,
+                    sha1: hash(1),
+                    md5: hash(2),
+                    sha256: None,
+                    file_name: data.fileName,
+                    name: None,
+                    author: None,
+                    website_url: None,
+                },
+                true,
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(|mut resolved| {
+            let mut fresh: Vec<&mut LockedMod> = resolved
+                .iter_mut()
+                .filter(|(_, fresh)| *fresh)
+                .map(|(m, _)| m)
+                .collect();
+            fill_project_info(&client, &mut fresh);
+            resolved.into_iter().map(|(m, _)| m).collect()
+        })
+}
+
+/// modlist用にプロジェクト名や作者を埋める. 取れなくてもビルドは止めない.
+fn fill_project_info(client: &Client, mods: &mut [&mut LockedMod]) {
+    let sleep = Duration::from_secs(5);
+    mods.par_iter_mut()
+        .filter(|m| m.name.is_none())
+        .for_each(|m| {
+            let url = format!("https://api.curse.tools/v1/cf/mods/{}", m.project_id);
+            match retry(|| get_json::<ModResponse>(client, &url), 3, sleep) {
+                Ok(response) => {
+                    let data = response.data;
+                    m.name = Some(data.name);
+                    m.author = data.authors.into_iter().next().map(|a| a.name);
+                    m.website_url = data.links.websiteUrl;
+                }
+                Err(e) => warn(format!(
+                    "failed to get project info of projectID={}: {}",
+                    m.project_id, e
+                )),
+            }
+        });
+}
+
+// downloadUrlがnullのときはCDNのURLを組み立てる
+fn cdn_url(file_id: u32, file_name: &str) -> String {
+    format!(
+        "https://mediafilez.forgecdn.net/files/{}/{}/{}",
+        file_id / 1000,
+        file_id % 1000,
+        file_name
+    )
 }
 
+/// 解決済みのMODをダウンロードし、サーバーパックに入れるjarのパス一覧を返す.
+/// ダウンロード先は全プロファイル共通なので、ディレクトリごとではなく返り値のjarだけを使うこと.
+/// ダウンロードしたjarのsha256は`mod_list`に書き戻す.
 pub fn fetchmods(
-    mod_list: &Vec<Mod>,
+    mod_list: &mut [LockedMod],
     output_folder: &Path,
     server_banned_mods: &[u32],
-) -> anyhow::Result<PathBuf> {
-    let sleep = Duration::from_secs(5); //APIがパンクしちゃうのでちょっと長めに待たせる
-
+    cache: &Cache,
+) -> anyhow::Result<Vec<PathBuf>> {
     let output_folder = output_folder.join("mods");
     fs::create_dir_all(&output_folder)?;
     let modcount = mod_list.len();
     let downloading_count = Mutex::new(0u16);
     let downloaded_count = Mutex::new(0u16);
+    let fetched = Mutex::new(Vec::new());
+    let mismatched = Mutex::new(Vec::new());
     let client = Client::new();
 
-    mod_list.par_iter().for_each(|cf_mod| {
+    mod_list.par_iter_mut().for_each(|cf_mod| {
+        let (project_id, file_id) = (cf_mod.project_id, cf_mod.file_id);
+        let file_name = cf_mod.file_name.clone();
+        let fields = || Fields::mod_file(project_id, file_id, &file_name);
         if server_banned_mods.contains(&cf_mod.project_id) {
-            warn(format!(
-                "skip detected client mod: (id: {})",
-                cf_mod.project_id
-            ));
-        } else {
-            info(format!(
-                "Downloading({:<03}/{:<03}) projectID={:<8} fileID={:<8}",
-                {
-                    let mut num = downloading_count.lock().unwrap();
-                    *num += 1;
-                    *num
-                },
-                modcount,
-                cf_mod.project_id,
-                cf_mod.file_id
-            ));
-            let url = format!(
-                "https://api.curse.tools/v1/cf/mods/{}/files/{}",
-                cf_mod.project_id, cf_mod.file_id
+            warn_with(
+                format!("skip detected client mod: (id: {})", cf_mod.project_id),
+                fields(),
             );
-            let response = retry(|| get_json(&client, &url), 5, sleep).unwrap();
-
-            // JSONからファイル名を確保
-            let file_name = response.data.fileName;
-            let file_path = output_folder.join(&file_name);
-
-            // JSONから有効なダウンロードURLを確保.
-            let download_url = response.data.downloadUrl;
-
-            // だうんろーど.
-            match fetch_file(&client, &download_url, &file_path) {
-                Ok(()) => info(format!(
-                    "Downloaded ({:<03}/{:<03}){}",
+        } else {
+            let started = Instant::now();
+            info_with(
+                format!(
+                    "Downloading({:<03}/{:<03}) projectID={:<8} fileID={:<8}",
                     {
-                        let mut num = downloaded_count.lock().unwrap();
+                        let mut num = downloading_count.lock().unwrap();
                         *num += 1;
                         *num
                     },
                     modcount,
-                    &file_name
-                )),
-                Err(DownloadError::Skipped) => warn(format!("{} has been skiped", &file_name)),
-                Err(e) => warn(format!("{} was not downloded: {:?}", &file_name, e)),
+                    cf_mod.project_id,
+                    cf_mod.file_id
+                ),
+                fields(),
+            );
+
+            let file_path = output_folder.join(&file_name);
+
+            // だうんろーど.
+            let downloaded = match cache.fetch(&client, &cf_mod.download_url, &file_path) {
+                Ok(()) => {
+                    info_with(
+                        format!(
+                            "Downloaded ({:<03}/{:<03}){}",
+                            {
+                                let mut num = downloaded_count.lock().unwrap();
+                                *num += 1;
+                                *num
+                            },
+                            modcount,
+                            &file_name
+                        ),
+                        Fields {
+                            duration_ms: Some(started.elapsed().as_millis()),
+                            ..fields()
+                        },
+                    );
+                    true
+                }
+                Err(DownloadError::Skipped) => {
+                    warn_with(format!("{} has been skiped", &file_name), fields());
+                    true
+                }
+                Err(e) => {
+                    warn_with(
+                        format!("{} was not downloded: {:?}", &file_name, e),
+                        fields(),
+                    );
+                    false
+                }
             };
+            if downloaded {
+                match verify_mod(cf_mod, &file_path) {
+                    Ok(()) => fetched.lock().unwrap().push(file_path),
+                    Err(e) => {
+                        // 壊れたjarは消して次回ダウンロードし直させる
+                        error_with(format!("{}: {}", &file_name, e), fields());
+                        let _ = fs::remove_file(&file_path);
+                        mismatched.lock().unwrap().push(file_name.clone());
+                    }
+                }
+            }
         };
     });
-    Ok(output_folder)
+    // ロックと中身が違うjarは欠けたままパックにせず、ビルドを止める
+    let mut mismatched = mismatched.into_inner().unwrap();
+    if !mismatched.is_empty() {
+        mismatched.sort();
+        return Err(anyhow!(
+            "{} mods failed hash verification: {}",
+            mismatched.len(),
+            mismatched.join(", ")
+        ));
+    }
+    // 並列で落とすので終わった順になる. 呼び出し側で順番が変わらないようにそろえる
+    let mut fetched = fetched.into_inner().unwrap();
+    fetched.sort();
+    Ok(fetched)
+}
+
+// ロックのハッシュと照合する. sha256が未記録なら記録する.
+fn verify_mod(cf_mod: &mut LockedMod, file_path: &Path) -> anyhow::Result<()> {
+    let hashes = hash_file(file_path)?;
+    if let Some(sha1) = &cf_mod.sha1
+        && *sha1 != hashes.sha1
+    {
+        return Err(anyhow!(
+            "sha1 mismatch (expected {}, got {})",
+            sha1,
+            hashes.sha1
+        ));
+    }
+    if let Some(sha256) = &cf_mod.sha256
+        && *sha256 != hashes.sha256
+    {
+        return Err(anyhow!(
+            "sha256 mismatch (expected {}, got {})",
+            sha256,
+            hashes.sha256
+        ));
+    }
+    cf_mod.sha256 = Some(hashes.sha256);
+    if cf_mod.size == 0 {
+        cf_mod.size = hashes.size;
+    }
+    Ok(())
+}
+
+/// `mod add`などで選んだファイル.
+pub struct ModFile {
+    pub file_id: u32,
+    pub file_name: String,
+}
+
+/// slugからprojectIDを探す. 同じslugが複数あればMOD(classId=6)を優先する.
+pub fn find_project(client: &Client, slug: &str) -> anyhow::Result<(u32, String)> {
+    let url = format!(
+        "https://api.curse.tools/v1/cf/mods/search?gameId=432&slug={}",
+        slug
+    );
+    let response: SearchResponse = retry(|| get_json(client, &url), 3, Duration::from_secs(5))?;
+    let mut found: Vec<_> = response
+        .data
+        .into_iter()
+        .filter(|p| p.slug == slug)
+        .collect();
+    found.sort_by_key(|p| p.classId != Some(6));
+    found
+        .into_iter()
+        .next()
+        .map(|p| (p.id, p.name))
+        .ok_or_else(|| anyhow!("no project with slug `{}` on CurseForge", slug))
+}
+
+/// パックのMinecraftバージョンとローダーに合う一番新しいファイル.
+/// `version`があれば、fileIDかファイル名/表示名にそれを含むものに絞る.
+pub fn latest_file(
+    client: &Client,
+    project_id: u32,
+    versions: &VersionSet,
+    version: Option<&str>,
+) -> anyhow::Result<ModFile> {
+    const PAGE_SIZE: usize = 50;
+    let matches = |f: &FileData| {
+        version.is_none_or(|v| {
+            f.id.to_string() == v || f.fileName.contains(v) || f.displayName.contains(v)
+        })
+    };
+    let mut found = Vec::new();
+    // 新しい順に返ってくるので、最新だけなら1ページ目で足りる
+    for index in (0..10000).step_by(PAGE_SIZE) {
+        let url = format!(
+            "https://api.curse.tools/v1/cf/mods/{}/files?gameVersion={}&modLoaderType={}&index={}&pageSize={}",
+            project_id,
+            versions.minecraft,
+            versions.loader_type.curseforge_type(),
+            index,
+            PAGE_SIZE
+        );
+        let response: FilesResponse = retry(|| get_json(client, &url), 3, Duration::from_secs(5))?;
+        let last_page = response.data.len() < PAGE_SIZE;
+        found.extend(response.data.into_iter().filter(|f| matches(f)));
+        if !found.is_empty() || last_page {
+            break;
+        }
+    }
+    // fileIDは新しいほど大きい
+    found
+        .into_iter()
+        .max_by_key(|f| f.id)
+        .map(|f| ModFile {
+            file_id: f.id,
+            file_name: f.fileName,
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "projectID={} has no file{} for Minecraft {} {}",
+                project_id,
+                version
+                    .map(|v| format!(" matching `{}`", v))
+                    .unwrap_or_default(),
+                versions.minecraft,
+                versions.id()
+            )
+        })
 }
@@ -0,0 +1,211 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use schemars::JsonSchema;
+use serde_derive::Deserialize;
+
+use crate::{config::Config, utils::pack_relative};
+
+pub const PACKIGNORE: &str = ".packignore";
+
+/// どちらのパック向けのコピーか.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Server,
+    Client,
+}
+
+impl Side {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Side::Server => "server",
+            Side::Client => "client",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Default, Clone)]
+pub struct FilterRules {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// override_dirs 1つ分のフィルタ設定.
+/// 共通のルールにサーバー/クライアント別のルールを足して使う.
+#[derive(Debug, Deserialize, JsonSchema, Default, Clone)]
+pub struct OverrideFilter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub server: FilterRules,
+    #[serde(default)]
+    pub client: FilterRules,
+}
+
+/// コピーするファイルを決めるフィルタ.
+/// includeが空なら全部対象で、そこからexcludeに当たったものを外す.
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    /// `override_dir`を`side`向けにコピーするときのフィルタを作る.
+    /// 設定のデフォルト除外、`override_filters`、ディレクトリ直下の`.packignore`を合わせる.
+    pub fn new(config: &Config, override_dir: &str, side: Side) -> Result<PathFilter> {
+        PathFilter::new_in(Path::new(""), config, override_dir, side)
+    }
+
+    /// `new`と同じだが、`.packignore`を`root`からの相対パスで探す.
+    /// gitのrefを一時ディレクトリに展開したものを見るときに使う.
+    pub fn new_in(
+        root: &Path,
+        config: &Config,
+        override_dir: &str,
+        side: Side,
+    ) -> Result<PathFilter> {
+        let mut include = Vec::new();
+        let mut exclude = config.default_config.override_excludes.clone();
+        exclude.push(PACKIGNORE.to_string());
+
+        let dir = pack_relative(override_dir)?;
+        for (key, filter) in &config.override_filters {
+            if pack_relative(key)? != dir {
+                continue;
+            }
+            let rules = match side {
+                Side::Server => &filter.server,
+                Side::Client => &filter.client,
+            };
+            include.extend(filter.include.iter().chain(&rules.include).cloned());
+            exclude.extend(filter.exclude.iter().chain(&rules.exclude).cloned());
+        }
+        exclude.extend(read_packignore(
+            &root.join(override_dir).join(PACKIGNORE),
+            side,
+        )?);
+
+        Ok(PathFilter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(build_globset(&include)?)
+            },
+            exclude: build_globset(&exclude)?,
+        })
+    }
+
+    /// `rel`はoverride_dirからの相対パス.
+    pub fn is_match(&self, rel: &Path) -> bool {
+        self.include.as_ref().is_none_or(|g| g.is_match(rel)) && !self.exclude.is_match(rel)
+    }
+}
+
+/// `.packignore`を読む. 1行1パターンで`#`から始まる行はコメント.
+/// `server:`/`client:`を頭に付けるとそのパックだけに効く.
+fn read_packignore(path: &Path, side: Side) -> Result<Vec<String>> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let raw_data = fs::read_to_string(path)?;
+    Ok(raw_data
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| match l.split_once(':') {
+            Some((prefix, pattern)) if prefix == side.name() => Some(pattern.trim().to_string()),
+            Some(("server" | "client", _)) => None,
+            _ => Some(l.to_string()),
+        })
+        .collect())
+}
+
+// gitignoreっぽく解釈する
+// - 末尾以外に`/`を含まないパターンはどの階層にも当たる
+// - `/`で終わるパターンはディレクトリの中身全部に当たる
+pub fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let body = pattern.trim_start_matches("./").trim_end_matches('/');
+        let p = match body.strip_prefix('/') {
+            Some(rooted) => rooted.to_string(),
+            None if body.contains('/') => body.to_string(),
+            None => format!("**/{}", body),
+        };
+        // ディレクトリ名だけ書かれても中身に当たるように`/**`版も入れる
+        for p in [p.clone(), format!("{}/**", p)] {
+            builder.add(
+                GlobBuilder::new(&p)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("invalid glob pattern: {}", pattern))?,
+            );
+        }
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn globset(patterns: &[&str]) -> GlobSet {
+        build_globset(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn pattern_without_slash_matches_any_depth() {
+        let set = globset(&["*.bak"]);
+        assert!(set.is_match("old.bak"));
+        assert!(set.is_match("jei/old.bak"));
+        assert!(!set.is_match("old.bak.txt"));
+    }
+
+    #[test]
+    fn trailing_slash_matches_directory_contents() {
+        let set = globset(&["jei/"]);
+        assert!(set.is_match("jei/bookmarks.ini"));
+        assert!(set.is_match("sub/jei/bookmarks.ini"));
+        assert!(!set.is_match("jei.txt"));
+    }
+
+    #[test]
+    fn leading_slash_and_inner_slash_are_anchored() {
+        let set = globset(&["/options.txt", "fancymenu/customization"]);
+        assert!(set.is_match("options.txt"));
+        assert!(!set.is_match("sub/options.txt"));
+        assert!(set.is_match("fancymenu/customization/layout.txt"));
+        assert!(!set.is_match("other/fancymenu/customization/layout.txt"));
+    }
+
+    #[test]
+    fn packignore_side_prefixes() {
+        let path = std::env::temp_dir().join(format!("mcpack-packignore-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# comment\n\n*.old\nserver: jei/\nclient:options.txt\nsome:thing\n",
+        )
+        .unwrap();
+        let server = read_packignore(&path, Side::Server).unwrap();
+        let client = read_packignore(&path, Side::Client).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(server, ["*.old", "jei/", "some:thing"]);
+        assert_eq!(client, ["*.old", "options.txt", "some:thing"]);
+    }
+
+    #[test]
+    fn include_then_exclude() {
+        let filter = PathFilter {
+            include: Some(globset(&["kubejs/"])),
+            exclude: globset(&["*.bak"]),
+        };
+        assert!(filter.is_match(Path::new("kubejs/startup.js")));
+        assert!(!filter.is_match(Path::new("kubejs/startup.js.bak")));
+        assert!(!filter.is_match(Path::new("config/a.toml")));
+    }
+}
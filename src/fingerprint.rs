@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{
+    archive::{file_mode, source_date_epoch},
+    logger::info,
+    utils::hash_file,
+};
+
+/// ステージの入力をまとめたハッシュ.
+/// 前回のビルドと同じなら、そのステージはやり直さずに前回の成果物を使う.
+pub struct Fingerprint {
+    hasher: Sha256,
+}
+
+impl Fingerprint {
+    pub fn new(stage: &str) -> Fingerprint {
+        let mut fp = Fingerprint {
+            hasher: Sha256::new(),
+        };
+        // ツール自体が変わったら出力も変わりうる
+        fp.add("tool", env!("CARGO_PKG_VERSION"));
+        fp.add("stage", stage);
+        fp.add("mtime", source_date_epoch().to_string());
+        fp
+    }
+
+    pub fn add(&mut self, label: &str, data: impl AsRef<[u8]>) {
+        let data = data.as_ref();
+        // 区切りが曖昧にならないように長さも入れる
+        self.hasher
+            .update(format!("{}:{}:", label, data.len()).as_bytes());
+        self.hasher.update(data);
+    }
+
+    /// 順番に意味の無いものを足す. 並べ替えてから入れるので、渡す順番が変わっても同じになる.
+    pub fn add_set<T: AsRef<[u8]>>(&mut self, label: &str, items: impl IntoIterator<Item = T>) {
+        let mut items: Vec<T> = items.into_iter().collect();
+        items.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        for item in items {
+            self.add(label, item);
+        }
+    }
+
+    /// ファイルかディレクトリの中身全部を足す. 無いパスは無いことを記録する.
+    pub fn add_path(&mut self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            self.add("missing", path.to_string_lossy().as_bytes());
+            return Ok(());
+        }
+        for entry in WalkDir::new(path).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let hashes = hash_file(entry.path())?;
+                self.add("file", entry.path().to_string_lossy().as_bytes());
+                self.add("sha256", hashes.sha256);
+                // 実行権限はアーカイブに残るので入力として扱う
+                self.add("mode", file_mode(entry.path()).to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+/// 前回のビルドのフィンガープリントを置く場所. `clean`で一緒に消える.
+fn record_path(outputfolder: &Path, key: &str) -> PathBuf {
+    outputfolder
+        .join(".fingerprints")
+        .join(format!("{}.sha256", key))
+}
+
+/// 前回と同じ入力で、成果物も残っているか.
+pub fn is_fresh(outputfolder: &Path, key: &str, fingerprint: &str, outputs: &[&Path]) -> bool {
+    let fresh = fs::read_to_string(record_path(outputfolder, key))
+        .is_ok_and(|last| last.trim() == fingerprint)
+        && outputs.iter().all(|p| p.is_file());
+    if fresh {
+        info(format!("[{}] inputs unchanged, skipped", key));
+    }
+    fresh
+}
+
+/// ステージを始める前に前回の記録を消す. 途中で失敗したときに古い記録が残らないようにする.
+pub fn invalidate(outputfolder: &Path, key: &str) -> Result<()> {
+    let path = record_path(outputfolder, key);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// ステージが最後まで終わったら記録する.
+pub fn save(outputfolder: &Path, key: &str, fingerprint: &str) -> Result<()> {
+    let path = record_path(outputfolder, key);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{}\n", fingerprint))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_set_ignores_order() {
+        let jars = ["a.jar", "b.jar", "c.jar", "d.jar"];
+        let mut forward = Fingerprint::new("server");
+        forward.add_set("jar", jars);
+        let mut backward = Fingerprint::new("server");
+        backward.add_set("jar", jars.iter().rev());
+        assert_eq!(forward.finish(), backward.finish());
+    }
+}
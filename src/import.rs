@@ -0,0 +1,122 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use zip::ZipArchive;
+
+use crate::{
+    config::{Config, Info, ManifestJson},
+    init::config_toml,
+    logger::{debug, info},
+    utils::write_schema,
+    validate::check_project,
+};
+
+/// CurseForgeのエクスポートzipをプロジェクトとして展開する.
+/// `overrides/`直下のディレクトリはそのままoverride_dirsに、直下のファイルはadditional_copy_filesにする.
+pub fn import(zip_path: &Path, overwrite: bool) -> Result<()> {
+    let config_path = Path::new("config.toml");
+    let manifest_path = Path::new("manifest.json");
+    let mut zip = ZipArchive::new(File::open(zip_path)?)
+        .with_context(|| format!("{} is not a zip file", zip_path.to_string_lossy()))?;
+
+    let manifest_text = {
+        let Ok(mut entry) = zip.by_name("manifest.json") else {
+            bail!(
+                "{} has no manifest.json (not a CurseForge pack?)",
+                zip_path.to_string_lossy()
+            );
+        };
+        let mut text = String::new();
+        entry.read_to_string(&mut text)?;
+        text
+    };
+    let manifest: ManifestJson = serde_json::from_str(&manifest_text)
+        .with_context(|| format!("{}: manifest.json is invalid", zip_path.to_string_lossy()))?;
+    let overrides = PathBuf::from(manifest.overrides());
+
+    // zip内のoverridesのファイルと展開先. パックの外を指すエントリは無視する
+    let mut files = Vec::new();
+    let mut dirs = BTreeSet::new();
+    let mut copy_files = BTreeSet::new();
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let Some(rel) = entry
+            .enclosed_name()
+            .and_then(|p| p.strip_prefix(&overrides).ok().map(Path::to_path_buf))
+            .filter(|p| p.components().all(|c| matches!(c, Component::Normal(_))))
+        else {
+            continue;
+        };
+        let mut components = rel.components();
+        let Some(top) = components.next() else {
+            continue;
+        };
+        let top = format!("./{}", top.as_os_str().to_string_lossy());
+        if components.next().is_some() {
+            dirs.insert(top);
+        } else {
+            copy_files.insert(top);
+        }
+        files.push((i, rel));
+    }
+
+    let mut targets = vec![config_path.to_path_buf(), manifest_path.to_path_buf()];
+    targets.extend(files.iter().map(|(_, rel)| rel.clone()));
+    if !overwrite && let Some(existing) = targets.iter().find(|p| p.exists()) {
+        bail!(
+            "{} already exists (use --overwrite to replace it)",
+            existing.to_string_lossy()
+        );
+    }
+
+    let pack = Info {
+        name: manifest.name.clone(),
+        version: manifest.version.clone(),
+        author: manifest.author.clone(),
+    };
+    let dirs: Vec<String> = dirs.into_iter().collect();
+    let copy_files: Vec<String> = copy_files.into_iter().collect();
+    let config_text = config_toml(&pack, &dirs, &copy_files);
+    let config: Config = toml::from_str(&config_text)?;
+    // override_dirsとadditional_copy_filesはこれから展開するので、無いことは見ない
+    let mut unpacked = config.clone();
+    unpacked.additional_copy_files.clear();
+    let mut result = check_project(&unpacked, &manifest);
+    result.warnings.clear();
+    result.report()?;
+
+    for (i, rel) in &files {
+        let mut entry = zip.by_index(*i)?;
+        if let Some(parent) = rel.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        debug(format!("[import] extract {}", rel.to_string_lossy()));
+        io::copy(&mut entry, &mut File::create(rel)?)?;
+    }
+    info(format!(
+        "Extracted {} files into {} override dirs and {} files",
+        files.len(),
+        dirs.len(),
+        copy_files.len()
+    ));
+
+    // manifestは元のまま置く. 書き直すとフィールドの順番などが変わってしまう
+    fs::write(manifest_path, manifest_text)?;
+    info(format!("Created {}", manifest_path.to_string_lossy()));
+    fs::write(config_path, config_text)?;
+    info(format!("Created {}", config_path.to_string_lossy()));
+    write_schema(Path::new("config.schema.json"))?;
+    info(format!(
+        "Imported {} {} by {}",
+        pack.name, pack.version, pack.author
+    ));
+    Ok(())
+}
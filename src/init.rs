@@ -0,0 +1,156 @@
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::Path,
+};
+
+use anyhow::{Result, bail};
+
+use crate::{
+    config::{Config, Info, ManifestJson, default_dirs},
+    logger::info,
+    utils::write_schema,
+    validate::check_project,
+};
+
+/// `init`で渡された値. 無いものは聞く.
+pub struct InitOptions {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub minecraft: Option<String>,
+    pub loader: Option<String>,
+    pub overwrite: bool,
+}
+
+/// カレントディレクトリに新しいパックの雛形を作る.
+pub fn init(options: InitOptions) -> Result<()> {
+    let config_path = Path::new("config.toml");
+    let manifest_path = Path::new("manifest.json");
+    if !options.overwrite {
+        for path in [config_path, manifest_path] {
+            if path.exists() {
+                bail!(
+                    "{} already exists (use --overwrite to replace it)",
+                    path.to_string_lossy()
+                );
+            }
+        }
+    }
+
+    let dir_name = std::env::current_dir()
+        .ok()
+        .and_then(|d| d.file_name().map(|n| n.to_string_lossy().into_owned()));
+    let pack = Info {
+        name: ask("Pack name", options.name, dir_name.as_deref())?,
+        author: ask("Author", options.author, None)?,
+        version: ask("Pack version", options.version, Some("1.0.0"))?,
+    };
+    let minecraft = ask("Minecraft version", options.minecraft, Some("1.20.1"))?;
+    let loader = ask(
+        "Mod loader (e.g. forge-47.3.0, neoforge-21.1.77, fabric-0.16.5)",
+        options.loader,
+        None,
+    )?;
+
+    let config_text = config_toml(&pack, &default_dirs(), &[]);
+    let manifest = ManifestJson::new(&pack, &minecraft, &loader);
+    // 書く前に`check`と同じ確認をして、壊れた雛形を残さないようにする
+    let config: Config = toml::from_str(&config_text)?;
+    let mut result = check_project(&config, &manifest);
+    // override_dirsはこれから作るので、無いという警告は出さない
+    result.warnings.clear();
+    result.report()?;
+
+    for dir in &config.override_dirs {
+        fs::create_dir_all(dir)?;
+        info(format!("Created {}", dir));
+    }
+
+    fs::write(config_path, config_text)?;
+    info(format!("Created {}", config_path.to_string_lossy()));
+    // config.tomlの`#:schema`が指すファイル. 無いとエディタが読めないと言う
+    write_schema(Path::new("config.schema.json"))?;
+    fs::write(manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    info(format!("Created {}", manifest_path.to_string_lossy()));
+    info("Add mods to manifest.json, then run `mcpack-builder build`");
+    Ok(())
+}
+
+/// 引数で渡されていればそれを使い、無ければ標準入力で聞く.
+fn ask(label: &str, value: Option<String>, default: Option<&str>) -> Result<String> {
+    if let Some(value) = value {
+        return Ok(value);
+    }
+    let stdin = io::stdin();
+    // パイプやCIでは聞けないので、既定値が無ければ失敗させる
+    if !stdin.is_terminal() {
+        return match default {
+            Some(d) => Ok(d.to_string()),
+            None => bail!("{} is required (stdin is not a terminal)", label),
+        };
+    }
+    loop {
+        match default {
+            Some(d) => print!("{} [{}]: ", label, d),
+            None => print!("{}: ", label),
+        }
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            bail!("{} is required", label);
+        }
+        let line = line.trim();
+        if !line.is_empty() {
+            return Ok(line.to_string());
+        }
+        if let Some(d) = default {
+            return Ok(d.to_string());
+        }
+    }
+}
+
+/// config-example.tomlの主な項目をコメント付きで書いたconfig.toml.
+pub fn config_toml(pack: &Info, override_dirs: &[String], copy_files: &[String]) -> String {
+    let list = |items: &[String]| {
+        items
+            .iter()
+            .map(|d| toml_string(d))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    format!(
+        r#"#:schema ./config.schema.json
+# `mcpack-builder schema`でconfig.schema.jsonを書き出すとエディタで補完とチェックが効く.
+
+# CurseForgeのmanifest.json
+manifest = "./manifest.json"
+# 解決済みのMOD情報の保存先. `--locked`を付けるとこれとmanifestのずれでビルドを失敗させる.
+lockfile = "./mcpack.lock"
+# overridesに含めるディレクトリ
+override_dirs = [{dirs}]
+# overridesに含めるファイル
+additional_copy_files = [{files}]
+# jarを探しに行くディレクトリ. 手動で落としたMODを置く.
+cache_dirs = []
+# クライアントパックに入れるmodlist(html/md)
+modlist_formats = ["html"]
+# サーバーにいらないMODのprojectID. よくあるものは最初から除外される.
+additional_noneeds_with_server = []
+
+[info]
+name = {name}
+version = {version}
+author = {author}
+"#,
+        dirs = list(override_dirs),
+        files = list(copy_files),
+        name = toml_string(&pack.name),
+        version = toml_string(&pack.version),
+        author = toml_string(&pack.author),
+    )
+}
+
+fn toml_string(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
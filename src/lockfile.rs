@@ -0,0 +1,257 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{config::Mod, modloader::VersionSet};
+
+const LOCK_VERSION: u32 = 1;
+
+/// 解決済みのMOD/ModLoaderの情報.
+/// これがあればメタデータAPIを叩かずにダウンロードできる.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LockFile {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loader: Option<LockedLoader>,
+    #[serde(default)]
+    pub mods: Vec<LockedMod>,
+}
+
+impl Default for LockFile {
+    fn default() -> Self {
+        Self {
+            version: LOCK_VERSION,
+            loader: None,
+            mods: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LockedMod {
+    pub project_id: u32,
+    pub file_id: u32,
+    pub file_name: String,
+    pub download_url: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    /// ダウンロード後に手元で計算する.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    // modlist用のプロジェクト情報
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub website_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LockedLoader {
+    /// manifest.jsonのmodLoaders[].id (例: forge-47.3.0)
+    pub id: String,
+    pub minecraft: String,
+    pub file_name: String,
+    pub download_url: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl LockFile {
+    /// ロックファイルを読む. 無ければ空のロックを返す.
+    pub fn read(path: &Path) -> Result<LockFile> {
+        if !path.exists() {
+            return Ok(LockFile::default());
+        }
+        let raw_data = fs::read_to_string(path)?;
+        let lock: LockFile = toml::from_str(&raw_data).context("failed to parse lock file")?;
+        Ok(lock)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let header = "# このファイルはmcpack-builderが自動生成します. 手で編集しないでください.\n";
+        let body = toml::to_string_pretty(self).context("failed to serialize lock file")?;
+        fs::write(path, format!("{}{}", header, body))?;
+        Ok(())
+    }
+
+    pub fn find_mod(&self, m: &Mod) -> Option<&LockedMod> {
+        self.mods
+            .iter()
+            .find(|l| l.project_id == m.project_id && l.file_id == m.file_id)
+    }
+
+    /// 解決結果を取り込む.
+    /// プロファイルごとに別のfileIDを使うこともあるので、projectIDとfileIDの組で管理する.
+    pub fn merge_mods(&mut self, resolved: &[LockedMod]) {
+        for r in resolved {
+            match self
+                .mods
+                .iter_mut()
+                .find(|l| l.project_id == r.project_id && l.file_id == r.file_id)
+            {
+                Some(l) => *l = r.clone(),
+                None => self.mods.push(r.clone()),
+            }
+        }
+        self.mods.sort_by_key(|l| (l.project_id, l.file_id));
+    }
+
+    /// どのmanifestからも参照されなくなったエントリを消す.
+    pub fn retain_mods(&mut self, used: &[Mod]) {
+        self.mods.retain(|l| {
+            used.iter()
+                .any(|m| m.project_id == l.project_id && m.file_id == l.file_id)
+        });
+    }
+
+    /// manifestとロックのずれを列挙する. 空ならずれていない.
+    pub fn drift(&self, used: &[Mod], loader: &VersionSet) -> Vec<String> {
+        let loader_id = loader.id();
+        let mut issues = Vec::new();
+        for m in used {
+            if self.find_mod(m).is_none() {
+                match self.mods.iter().find(|l| l.project_id == m.project_id) {
+                    Some(l) => issues.push(format!(
+                        "projectID={} fileID changed: {} (lock) -> {} (manifest)",
+                        m.project_id, l.file_id, m.file_id
+                    )),
+                    None => issues.push(format!(
+                        "projectID={} fileID={} is not in lock file",
+                        m.project_id, m.file_id
+                    )),
+                }
+            }
+        }
+        for l in &self.mods {
+            if !used.iter().any(|m| m.project_id == l.project_id) {
+                issues.push(format!(
+                    "projectID={} is locked but not in manifest",
+                    l.project_id
+                ));
+            }
+        }
+        match &self.loader {
+            Some(locked) if locked.id != loader_id => issues.push(format!(
+                "loader changed: {} (lock) -> {} (manifest)",
+                locked.id, loader_id
+            )),
+            // インストーラーの無いローダーはロックに載らない
+            None if loader.loader_type.has_installer() => {
+                issues.push(format!("loader {} is not in lock file", loader_id))
+            }
+            _ => {}
+        }
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(project_id: u32, file_id: u32) -> LockedMod {
+        LockedMod {
+            project_id,
+            file_id,
+            file_name: format!("{}-{}.jar", project_id, file_id),
+            download_url: String::new(),
+            size: 0,
+            sha1: None,
+            md5: None,
+            sha256: None,
+            name: None,
+            author: None,
+            website_url: None,
+        }
+    }
+
+    fn used(project_id: u32, file_id: u32) -> Mod {
+        Mod {
+            project_id,
+            file_id,
+            required: true,
+        }
+    }
+
+    fn loader(id: &str) -> LockedLoader {
+        LockedLoader {
+            id: id.to_string(),
+            minecraft: "1.20.1".to_string(),
+            file_name: String::new(),
+            download_url: String::new(),
+            size: 0,
+            sha1: None,
+            sha256: None,
+        }
+    }
+
+    fn forge() -> VersionSet {
+        VersionSet::parse("1.20.1", "forge-47.3.0").unwrap()
+    }
+
+    #[test]
+    fn merge_keeps_profile_file_ids_apart() {
+        let mut lock = LockFile::default();
+        lock.merge_mods(&[locked(2, 20), locked(1, 10)]);
+        // プロファイルで別のfileIDを使っている
+        lock.merge_mods(&[locked(1, 11)]);
+        let mut renamed = locked(1, 10);
+        renamed.file_name = "renamed.jar".to_string();
+        lock.merge_mods(&[renamed]);
+
+        let keys: Vec<_> = lock
+            .mods
+            .iter()
+            .map(|m| (m.project_id, m.file_id))
+            .collect();
+        assert_eq!(keys, [(1, 10), (1, 11), (2, 20)]);
+        assert_eq!(lock.mods[0].file_name, "renamed.jar");
+    }
+
+    #[test]
+    fn drift_is_empty_when_in_sync() {
+        let mut lock = LockFile::default();
+        lock.merge_mods(&[locked(1, 10), locked(1, 11)]);
+        lock.loader = Some(loader("forge-47.3.0"));
+        assert!(lock.drift(&[used(1, 10), used(1, 11)], &forge()).is_empty());
+    }
+
+    #[test]
+    fn drift_reports_changes() {
+        let mut lock = LockFile::default();
+        lock.merge_mods(&[locked(1, 10), locked(3, 30)]);
+        lock.loader = Some(loader("forge-47.2.0"));
+        let issues = lock.drift(&[used(1, 12), used(2, 20)], &forge());
+        assert_eq!(
+            issues,
+            [
+                "projectID=1 fileID changed: 10 (lock) -> 12 (manifest)",
+                "projectID=2 fileID=20 is not in lock file",
+                "projectID=3 is locked but not in manifest",
+                "loader changed: forge-47.2.0 (lock) -> forge-47.3.0 (manifest)",
+            ]
+        );
+    }
+
+    #[test]
+    fn drift_needs_locked_loader_only_with_installer() {
+        let lock = LockFile::default();
+        assert_eq!(
+            lock.drift(&[], &forge()),
+            ["loader forge-47.3.0 is not in lock file"]
+        );
+        let fabric = VersionSet::parse("1.20.1", "fabric-0.16.5").unwrap();
+        assert!(lock.drift(&[], &fabric).is_empty());
+    }
+}
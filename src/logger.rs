@@ -1,21 +1,178 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::Write,
-    sync::Mutex,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    time::Duration,
 };
 
-use chrono::{DateTime, FixedOffset, Utc};
+use anyhow::{Context, anyhow};
+use chrono::{FixedOffset, Local, Utc};
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
 
 static LOGGER: Mutex<Option<File>> = Mutex::new(None);
+// 画面とファイルでそれぞれこのレベル以上だけ出す
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static FILE_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+static USE_COLOR: AtomicBool = AtomicBool::new(false);
+static TIMEZONE: Mutex<Timezone> = Mutex::new(Timezone::Local);
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+// 今のステージ. JSON形式のときに各行に付ける.
+static STAGE: Mutex<Option<String>> = Mutex::new(None);
 
-/// ログレベル
-#[derive(Debug)]
-enum LogLevel {
+/// ログレベル. 下に行くほど重要.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
     Info,
     Warn,
     Error,
 }
 
+impl LogLevel {
+    const ALL: [LogLevel; 5] = [
+        LogLevel::Trace,
+        LogLevel::Debug,
+        LogLevel::Info,
+        LogLevel::Warn,
+        LogLevel::Error,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+
+    /// `-v`の数だけ細かく、`-q`の数だけ静かにする.
+    pub fn shift(self, verbose: u8, quiet: u8) -> LogLevel {
+        let i = (self as i32 - verbose as i32 + quiet as i32).clamp(0, 4);
+        LogLevel::ALL[i as usize]
+    }
+}
+
+/// `[log]`の設定.
+#[derive(Debug, Deserialize, JsonSchema, Default, Clone)]
+pub struct LogConfig {
+    /// 画面に出す最低レベル. `-v`/`-q`でここから上下する.
+    pub level: Option<LogLevel>,
+    /// ログファイルに書く最低レベル. 省略時はdebug.
+    pub file_level: Option<LogLevel>,
+    /// タイムスタンプのタイムゾーン. `local`/`utc`/`+09:00`のような固定オフセット.
+    #[serde(default)]
+    #[schemars(with = "String")]
+    pub timezone: Timezone,
+    /// 出力形式. CIで読むなら`json`.
+    pub format: Option<LogFormat>,
+    /// `distribution/logs`に残すログファイルの数. 0なら全部残す.
+    #[serde(default = "default_keep_logs")]
+    pub keep_logs: usize,
+}
+
+fn default_keep_logs() -> usize {
+    10
+}
+
+/// ログの出力形式. jsonは1行1オブジェクトで、画面にもファイルにもこの形式で出す.
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// 構造化ログの追加フィールド. JSON形式のときだけ出力に載る.
+#[derive(Debug, Serialize, Default)]
+pub struct Fields {
+    #[serde(rename = "projectID", skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<u32>,
+    #[serde(rename = "fileID", skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<u32>,
+    #[serde(rename = "fileName", skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(rename = "durationMs", skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u128>,
+}
+
+impl Fields {
+    pub fn mod_file(project_id: u32, file_id: u32, file_name: &str) -> Fields {
+        Fields {
+            project_id: Some(project_id),
+            file_id: Some(file_id),
+            file_name: Some(file_name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn duration(duration: Duration) -> Fields {
+        Fields {
+            duration_ms: Some(duration.as_millis()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: &'a str,
+    level: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<String>,
+    #[serde(flatten)]
+    fields: &'a Fields,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(try_from = "String")]
+pub enum Timezone {
+    #[default]
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "local" => Ok(Timezone::Local),
+            "utc" | "z" => Ok(Timezone::Utc),
+            offset => offset
+                .parse::<FixedOffset>()
+                .map(Timezone::Fixed)
+                .map_err(|_| anyhow!("invalid timezone `{}`", value))
+                .context("expected `local`, `utc` or an offset like `+09:00`"),
+        }
+    }
+}
+
+impl Timezone {
+    fn timestamp(&self) -> String {
+        // RFC 3339 (例: 2025-01-02T03:04:05.678+09:00)
+        const FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+        match self {
+            Timezone::Local => Local::now().format(FORMAT).to_string(),
+            Timezone::Utc => Utc::now().format(FORMAT).to_string(),
+            Timezone::Fixed(offset) => Utc::now().with_timezone(offset).format(FORMAT).to_string(),
+        }
+    }
+}
+
 impl LevelSet {
     fn from_str(level: &str, color: &str) -> LevelSet {
         LevelSet {
@@ -31,52 +188,150 @@ struct LevelSet {
 }
 
 /// Loggerの初期化
-pub fn init_logger(log_path: &str) {
+/// ビルドごとに`log_dir/<timestamp>.log`を作って書き込む. 作ったファイルのパスを返す.
+pub fn init_logger(log_dir: &Path) -> PathBuf {
+    fs::create_dir_all(log_dir).expect("Failed to create log directory");
+    // 名前順に並べると古い順になるようにする
+    let log_path = log_dir.join(format!("{}.log", Local::now().format("%Y%m%d-%H%M%S-%3f")));
     let file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(log_path)
+        .open(&log_path)
         .expect("Failed to open log file");
     let mut logger = LOGGER.lock().unwrap();
     *logger = Some(file);
+
+    // 色を付けるのは端末に出すときだけ. NO_COLORが空でなければ付けない(https://no-color.org)
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    USE_COLOR.store(!no_color && io::stdout().is_terminal(), Ordering::Relaxed);
+    log_path
+}
+
+/// 古いログファイルを消して新しい`keep`個だけ残す. 0なら全部残す.
+pub fn prune_logs(log_dir: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let mut logs: Vec<PathBuf> = fs::read_dir(log_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    logs.sort();
+    let stale = logs.len().saturating_sub(keep);
+    for path in &logs[..stale] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+pub fn set_timezone(timezone: Timezone) {
+    *TIMEZONE.lock().unwrap() = timezone;
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON_FORMAT.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// 今のステージを設定する. 以降のJSONログの`stage`になる.
+pub fn set_stage<S: Into<String>>(stage: S) {
+    *STAGE.lock().unwrap() = Some(stage.into());
+}
+
+/// 出力するレベルを変える. 設定とコマンドラインを読んだあとに呼ぶ.
+pub fn set_levels(console: LogLevel, file: LogLevel) {
+    CONSOLE_LEVEL.store(console as u8, Ordering::Relaxed);
+    FILE_LEVEL.store(file as u8, Ordering::Relaxed);
 }
 
 /// ログ出力関数
-fn log<S: Into<String>>(level: LogLevel, msg: S) {
-    let now: DateTime<FixedOffset> =
-        Utc::now().with_timezone(&FixedOffset::east_opt(9 * 3600).unwrap()); //JST
-    let timestamp = now.format("%Y-%m-%dZ%H:%M:%S%.3f %Z").to_string();
+fn log<S: Into<String>>(level: LogLevel, msg: S, fields: &Fields) {
+    let to_console = level as u8 >= CONSOLE_LEVEL.load(Ordering::Relaxed);
+    let to_file = level as u8 >= FILE_LEVEL.load(Ordering::Relaxed);
+    if !to_console && !to_file {
+        return;
+    }
+    let timestamp = TIMEZONE.lock().unwrap().timestamp();
 
     let level_set: LevelSet = match level {
-        LogLevel::Info => LevelSet::from_str("INFO", "\x1b[32;40m"), //green
-        LogLevel::Warn => LevelSet::from_str("WARN", "\x1b[33;40m"), //yellow
-        LogLevel::Error => LevelSet::from_str("ERROR", "\x1b[31;40m"), //red
+        LogLevel::Trace => LevelSet::from_str("TRACE", "\x1b[90m"), //gray
+        LogLevel::Debug => LevelSet::from_str("DEBUG", "\x1b[36m"), //cyan
+        LogLevel::Info => LevelSet::from_str("INFO", "\x1b[32m"),   //green
+        LogLevel::Warn => LevelSet::from_str("WARN", "\x1b[33m"),   //yellow
+        LogLevel::Error => LevelSet::from_str("ERROR", "\x1b[31m"), //red
     };
 
-    let message = format!(
-        "\x1b[90;40m{0: <31}{reset}{color}{1: <8}{2:}{reset}\n",
-        timestamp,
-        level_set.level,
-        msg.into(),
-        color = level_set.color,
-        reset = "\x1b[0m"
-    );
+    let msg = msg.into();
 
-    print!("{}", message);
+    if JSON_FORMAT.load(Ordering::Relaxed) {
+        let line = serde_json::to_string(&JsonLine {
+            timestamp: &timestamp,
+            level: level.name(),
+            message: &msg,
+            stage: STAGE.lock().unwrap().clone(),
+            fields,
+        })
+        .unwrap_or_default();
+        if to_console {
+            println!("{}", line);
+        }
+        let mut logger = LOGGER.lock().unwrap();
+        if to_file && let Some(file) = logger.as_mut() {
+            let _ = writeln!(file, "{}", line);
+        }
+        return;
+    }
+
+    if to_console {
+        if USE_COLOR.load(Ordering::Relaxed) {
+            println!(
+                "\x1b[90m{0: <30}{reset}{color}{1: <8}{2:}{reset}",
+                timestamp,
+                level_set.level,
+                msg,
+                color = level_set.color,
+                reset = "\x1b[0m"
+            );
+        } else {
+            println!("{0: <30}{1: <8}{2:}", timestamp, level_set.level, msg);
+        }
+    }
 
+    // ファイルには色を付けない
     let mut logger = LOGGER.lock().unwrap();
-    if let Some(file) = logger.as_mut() {
-        let _ = file.write_all(message.as_bytes());
+    if to_file && let Some(file) = logger.as_mut() {
+        let _ = writeln!(file, "{0: <30}{1: <8}{2:}", timestamp, level_set.level, msg);
     }
 }
 
+pub fn trace<S: Into<String>>(msg: S) {
+    log(LogLevel::Trace, msg, &Fields::default());
+}
+
+pub fn debug<S: Into<String>>(msg: S) {
+    log(LogLevel::Debug, msg, &Fields::default());
+}
+
 pub fn info<S: Into<String>>(msg: S) {
-    log(LogLevel::Info, msg);
+    log(LogLevel::Info, msg, &Fields::default());
 }
 
 pub fn warn<S: Into<String>>(msg: S) {
-    log(LogLevel::Warn, msg);
+    log(LogLevel::Warn, msg, &Fields::default());
 }
 pub fn error<S: Into<String>>(msg: S) {
-    log(LogLevel::Error, msg);
+    log(LogLevel::Error, msg, &Fields::default());
+}
+
+// 構造化フィールド付き. テキスト形式では`msg`だけ出る.
+pub fn info_with<S: Into<String>>(msg: S, fields: Fields) {
+    log(LogLevel::Info, msg, &fields);
+}
+
+pub fn warn_with<S: Into<String>>(msg: S, fields: Fields) {
+    log(LogLevel::Warn, msg, &fields);
+}
+
+pub fn error_with<S: Into<String>>(msg: S, fields: Fields) {
+    log(LogLevel::Error, msg, &fields);
 }
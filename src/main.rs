@@ -1,114 +1,208 @@
-use std::{
-    fs::{self},
-    path::{Path, PathBuf},
-};
+use std::path::Path;
 
+use anyhow::bail;
 use clap::Parser;
-use config::Args;
+use config::{Args, Command};
 
 use crate::{
-    curseforge::fetchmods,
-    logger::{info, init_logger},
-    modloader::{VersionSet, fetch_modloader},
-    utils::{copy_dir, directory_archive, read_config, read_manifest_json},
+    build::{Target, build_pack, check_offline, clean, prune_downloads},
+    cache::Cache,
+    changelog::changelog,
+    import::import,
+    init::{InitOptions, init},
+    lockfile::LockFile,
+    logger::{
+        LogLevel, debug, error, info, init_logger, prune_logs, set_format, set_levels, set_stage,
+        set_timezone, trace,
+    },
+    modloader::VersionSet,
+    mods::mod_command,
+    report::write_report,
+    utils::{read_config, read_manifest_json, write_schema},
+    validate::check_project,
+    vendor::vendor,
 };
 
+mod archive;
 mod build;
+mod cache;
+mod changelog;
 mod config;
 mod curseforge;
+mod filter;
+mod fingerprint;
+mod import;
+mod init;
+mod lockfile;
 mod logger;
+mod modlist;
 mod modloader;
+mod mods;
+mod report;
+mod template;
 mod utils;
+mod validate;
+mod vendor;
 
 #[warn(unused_extern_crates)]
 fn main() -> anyhow::Result<()> {
-    init_logger("app.log");
+    let args = Args::parse();
+    let outputfolder = Path::new("./distribution");
+    let log_path = init_logger(&outputfolder.join("logs"));
+    set_levels(
+        LogLevel::Info.shift(args.verbose, args.quiet),
+        LogLevel::Debug,
+    );
+    set_format(args.log_format.unwrap_or_default());
     info("Hello there!");
     //warn("WARNING!");
     //error("Oops.");
-    let _args = Args::parse();
-    let config = read_config(Path::new("config.toml"))?;
-    let manifest = read_manifest_json(Path::new(&config.manifest))?;
-    info(format!("{:?}", config));
-    info(format!("{:?}", manifest));
-    let outputfolder = Path::new("./distribution");
-    let mut noneed_with_server = config.default_config.no_needs_with_server;
-    let mut noneed_config = config.additional_noneeds_with_server;
-    noneed_with_server.append(&mut noneed_config);
 
-    // CurseForgeからサーバーパック用のmodを取得
-    // Server ID BANからクライアント系MODは除外済み
-    let mods_path = {
-        let mut path = PathBuf::new();
-        if let Some(files) = &manifest.files {
-            path = fetchmods(files, outputfolder, &noneed_with_server)?;
-        };
-        path
-    };
+    let result = run(&args, outputfolder);
+    // JSON形式でも失敗がわかるようにログにも出す
+    if let Err(e) = &result {
+        error(format!("{:#}", e));
+        error(format!(
+            "Failed. See {} for the full log",
+            log_path.to_string_lossy()
+        ));
+    }
+    result
+}
+
+fn run(args: &Args, outputfolder: &Path) -> anyhow::Result<()> {
+    match &args.command {
+        Some(Command::Clean) => return clean(outputfolder),
+        Some(Command::Schema { out }) => return write_schema(out),
+        Some(Command::Import { zip, overwrite }) => return import(zip, *overwrite),
+        Some(Command::Init {
+            name,
+            author,
+            version,
+            minecraft,
+            loader,
+            overwrite,
+        }) => {
+            return init(InitOptions {
+                name: name.clone(),
+                author: author.clone(),
+                version: version.clone(),
+                minecraft: minecraft.clone(),
+                loader: loader.clone(),
+                overwrite: *overwrite,
+            });
+        }
+        _ => {}
+    }
 
-    info("get curseforge mods is end!");
+    let config = read_config(Path::new("config.toml"), args.strict)?;
+    set_levels(
+        config
+            .log
+            .level
+            .unwrap_or(LogLevel::Info)
+            .shift(args.verbose, args.quiet),
+        config.log.file_level.unwrap_or(LogLevel::Debug),
+    );
+    set_timezone(config.log.timezone);
+    set_format(args.log_format.or(config.log.format).unwrap_or_default());
+    prune_logs(&outputfolder.join("logs"), config.log.keep_logs)?;
+    debug(format!("{:?}", config));
+    let manifest = read_manifest_json(Path::new(&config.manifest))?;
+    trace(format!("{:?}", manifest));
 
-    let loader: &Vec<_> = &manifest.minecraft.mod_loaders[0].id.split('-').collect();
+    // 壊れたmanifestを直すのにも使うので、確認より前に処理する
+    if let Some(Command::Mod { command }) = &args.command {
+        return mod_command(command, &config, &manifest, args.offline);
+    }
 
-    let versionset = VersionSet {
-        minecraft: manifest.minecraft.version.clone(),
-        loader: loader[1].to_string(),
-        loader_type: match loader[0] {
-            "forge" => modloader::LoaderType::Forge,
-            _ => modloader::LoaderType::Fabric,
-        },
-    };
-    let loader_save_path = outputfolder.join("./loader");
-    fs::create_dir_all(&loader_save_path)?;
+    // 変な入力で途中でpanicしないように、ビルド前にまとめて確認する
+    if !matches!(args.command, Some(Command::Changelog { .. })) {
+        check_project(&config, &manifest).report()?;
+    }
+    if let Some(Command::Check) = &args.command {
+        info("config.toml and manifest.json look good");
+        return Ok(());
+    }
 
-    let pack_name = format!("{}-v{}", config.info.name, config.info.version);
-    let pack_path = outputfolder.join("./exported");
-    fs::create_dir_all(&pack_path)?;
+    let targets = Target::list(&config, &manifest, args.profile.as_deref())?;
 
-    fetch_modloader(&versionset, &loader_save_path);
-    '_build_server_package: {
-        let server_pack_path = outputfolder.join("./.server");
-        copy_dir(&mods_path, &server_pack_path.join("./mods"))?;
-        let override_dirs = &config.override_dirs;
-        for override_dir in override_dirs {
-            copy_dir(
-                Path::new(&override_dir),
-                &server_pack_path.join(override_dir),
-            )?;
+    let lock_path = Path::new(&config.lockfile);
+    let mut lock = LockFile::read(lock_path)?;
+    // ロックファイルは全プロファイル共通なので、--profileで絞っても全プロファイル分を見る
+    let used_mods = Target::used_mods(&Target::list(&config, &manifest, None)?);
+    if args.locked {
+        let issues = lock.drift(&used_mods, &VersionSet::from_manifest(&manifest)?);
+        if !issues.is_empty() {
+            for issue in &issues {
+                error(issue);
+            }
+            bail!(
+                "manifest has drifted from {} ({} issues)",
+                config.lockfile,
+                issues.len()
+            );
         }
-        let archive_name = pack_path.join(format!("{}-server.zip", pack_name));
-        info(archive_name.to_string_lossy());
-        directory_archive(&server_pack_path, &archive_name)?;
-    };
-    '_build_Client_package: {
-        let client_pack_path = outputfolder.join("./.client");
-        let client_overrides_path = outputfolder.join("./.client/override");
+    }
 
-        let override_dirs = &config.override_dirs;
-        for override_dir in override_dirs {
-            copy_dir(
-                Path::new(&override_dir),
-                &client_overrides_path.join(override_dir),
-            )?;
-        }
-        let mut manifest_json = manifest;
-        manifest_json.name = config.info.name;
-        manifest_json.version = config.info.version;
-        manifest_json.author = config.info.author;
+    let cache = Cache::new(&config.cache_dirs, args.offline);
 
-        let manifest_path = client_pack_path.join("./manifest.json");
-        let json_str = serde_json::to_string_pretty(&manifest_json)?;
-        std::fs::write(&manifest_path, json_str)?;
-        let mut additional_files = config.additional_copy_files;
-        additional_files.push("./modlist.html".to_string());
-        for f in additional_files {
-            //後でディレクトリコピーから単一ファイルコピー関数を切り出しておく
-            fs::copy(Path::new(&f), outputfolder.join(&f))?;
+    match &args.command {
+        None | Some(Command::Build { .. }) => {
+            if args.offline {
+                let missing = check_offline(&targets, &lock, &cache, outputfolder);
+                if !missing.is_empty() {
+                    for m in &missing {
+                        error(m);
+                    }
+                    bail!("{} files are missing for offline build", missing.len());
+                }
+            }
+
+            let server_format = match &args.command {
+                Some(Command::Build { server_format }) => *server_format,
+                _ => None,
+            };
+            let mut reports = Vec::new();
+            for mut target in targets {
+                if let Some(format) = server_format {
+                    target.config.archive.server_format = format;
+                }
+                reports.push(build_pack(
+                    target,
+                    outputfolder,
+                    &mut lock,
+                    &cache,
+                    args.force,
+                )?);
+            }
+            write_report(outputfolder, reports)?;
+        }
+        Some(
+            Command::Clean
+            | Command::Check
+            | Command::Schema { .. }
+            | Command::Init { .. }
+            | Command::Import { .. }
+            | Command::Mod { .. },
+        ) => {
+            unreachable!()
+        }
+        Some(Command::Vendor { dir }) => {
+            set_stage("vendor");
+            vendor(&targets, &mut lock, &cache, dir)?
         }
+        Some(Command::Changelog { from, to, out_dir }) => {
+            // 比較するだけなのでロックファイルは書き換えない
+            return changelog(&config, &lock, &cache, from, to.as_deref(), out_dir);
+        }
+    }
 
-        let archive_name = pack_path.join(format!("{}-client.zip", pack_name));
-        info(archive_name.to_string_lossy());
-        directory_archive(&client_pack_path, &archive_name)?;
+    if !args.locked {
+        lock.retain_mods(&used_mods);
+        lock.write(lock_path)?;
+        info(format!("Lock file written to {}", config.lockfile));
     }
+    prune_downloads(outputfolder, &lock)?;
     Ok(())
 }
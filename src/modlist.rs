@@ -0,0 +1,113 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{lockfile::LockedMod, logger::info};
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModlistFormat {
+    Html,
+    Md,
+    Json,
+}
+
+impl ModlistFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ModlistFormat::Html => "modlist.html",
+            ModlistFormat::Md => "modlist.md",
+            ModlistFormat::Json => "modlist.json",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ModlistEntry<'a> {
+    #[serde(rename = "projectID")]
+    project_id: u32,
+    #[serde(rename = "fileID")]
+    file_id: u32,
+    name: &'a str,
+    author: Option<&'a str>,
+    url: String,
+    #[serde(rename = "fileName")]
+    file_name: &'a str,
+}
+
+/// modlistを`dir`に書き出す.
+/// カレントディレクトリに同名のファイルがあれば、生成せずにそれをコピーする.
+pub fn write_modlist(mods: &[LockedMod], formats: &[ModlistFormat], dir: &Path) -> Result<()> {
+    let mut entries: Vec<ModlistEntry> =
+        mods.iter()
+            .map(|m| ModlistEntry {
+                project_id: m.project_id,
+                file_id: m.file_id,
+                name: m.name.as_deref().unwrap_or(&m.file_name),
+                author: m.author.as_deref(),
+                url: m.website_url.clone().unwrap_or_else(|| {
+                    format!("https://www.curseforge.com/projects/{}", m.project_id)
+                }),
+                file_name: &m.file_name,
+            })
+            .collect();
+    entries.sort_by_key(|e| e.name.to_lowercase());
+
+    for format in formats {
+        let file_name = format.file_name();
+        let save_path = dir.join(file_name);
+        let user_file = Path::new(file_name);
+        if user_file.is_file() {
+            info(format!("Use user provided {}", file_name));
+            fs::copy(user_file, &save_path)?;
+            continue;
+        }
+        let body = match format {
+            ModlistFormat::Html => html(&entries),
+            ModlistFormat::Md => markdown(&entries),
+            ModlistFormat::Json => serde_json::to_string_pretty(&entries)?,
+        };
+        fs::write(&save_path, body)?;
+        info(format!("Generated {}", save_path.to_string_lossy()));
+    }
+    Ok(())
+}
+
+// CurseForgeアプリが書き出すのと同じ形式
+fn html(entries: &[ModlistEntry]) -> String {
+    let mut out = String::from("<ul>\n");
+    for e in entries {
+        out += &format!(
+            "<li><a href=\"{}\">{}{}</a></li>\n",
+            escape(&e.url),
+            escape(e.name),
+            e.author
+                .map(|a| format!(" (by {})", escape(a)))
+                .unwrap_or_default()
+        );
+    }
+    out += "</ul>\n";
+    out
+}
+
+fn markdown(entries: &[ModlistEntry]) -> String {
+    let mut out = String::new();
+    for e in entries {
+        out += &format!(
+            "- [{}]({}){}\n",
+            e.name.replace('[', "\\[").replace(']', "\\]"),
+            e.url,
+            e.author.map(|a| format!(" by {}", a)).unwrap_or_default()
+        );
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
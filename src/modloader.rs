@@ -1,10 +1,14 @@
 use std::path::Path;
 
+use anyhow::{Result, anyhow, bail};
 use reqwest::blocking::Client;
 
 use crate::{
+    cache::Cache,
+    config::ManifestJson,
+    lockfile::LockedLoader,
     logger::{error, info},
-    utils::fetch_file,
+    utils::{DownloadError, hash_file},
 };
 
 #[allow(warnings)]
@@ -15,6 +19,31 @@ pub enum LoaderType {
 }
 
 impl LoaderType {
+    /// manifest.jsonのmodLoaders[].idの`-`より前の部分から.
+    pub fn from_name(name: &str) -> Option<LoaderType> {
+        match name {
+            "forge" => Some(LoaderType::Forge),
+            "neoforge" => Some(LoaderType::NeoForge),
+            "fabric" => Some(LoaderType::Fabric),
+            _ => None,
+        }
+    }
+
+    /// CurseForge APIの`modLoaderType`.
+    pub fn curseforge_type(&self) -> u8 {
+        match self {
+            LoaderType::Forge => 1,
+            LoaderType::Fabric => 4,
+            LoaderType::NeoForge => 6,
+        }
+    }
+
+    /// サーバー用のインストーラーを配っているか.
+    /// Fabricはインストーラーのバージョンが別管理なので、ここでは落とさない.
+    pub fn has_installer(&self) -> bool {
+        !matches!(self, LoaderType::Fabric)
+    }
+
     fn get_name(&self) -> &'static str {
         match self {
             LoaderType::Forge => "forge",
@@ -44,14 +73,11 @@ impl VersionSet {
             }
             LoaderType::NeoForge => {
                 format!(
-                    "https://maven.neoforged.net/releases/net/neoforged/neoforge/{mc_ver}.167/neoforge-{loader_version}-installer.jar",
-                    mc_ver = self
-                        .loader
-                        .get(9..self.loader.len() - 1)
-                        .expect("Format Error!"), // neoforge. で9文字.
+                    "https://maven.neoforged.net/releases/net/neoforged/neoforge/{loader_version}/neoforge-{loader_version}-installer.jar",
                     loader_version = self.loader
                 )
             }
+            // has_installerで先に弾くので使われない
             LoaderType::Fabric => String::new(),
         }
     }
@@ -63,19 +89,130 @@ pub struct VersionSet {
     pub loader_type: LoaderType,
 }
 
-pub fn fetch_modloader(version_set: &VersionSet, path: &Path) {
-    let client = Client::new();
-    match fetch_file(
-        &client,
-        &version_set.url(),
-        &path.join(format!(
+impl VersionSet {
+    /// primaryのModLoader(無ければ先頭)を使う.
+    pub fn from_manifest(manifest: &ManifestJson) -> Result<VersionSet> {
+        let loaders = &manifest.minecraft.mod_loaders;
+        let loader = loaders
+            .iter()
+            .find(|l| l.primary)
+            .or(loaders.first())
+            .ok_or_else(|| anyhow!("minecraft.modLoaders is empty"))?;
+        VersionSet::parse(&manifest.minecraft.version, &loader.id)
+    }
+
+    /// `forge-47.3.0`のようなidを読む.
+    pub fn parse(minecraft: &str, id: &str) -> Result<VersionSet> {
+        let Some((name, version)) = id.split_once('-') else {
+            bail!(
+                "invalid mod loader id `{}` (expected like `forge-47.3.0`)",
+                id
+            );
+        };
+        let Some(loader_type) = LoaderType::from_name(name) else {
+            bail!(
+                "unknown mod loader `{}` in `{}` (forge/neoforge/fabric)",
+                name,
+                id
+            );
+        };
+        if !is_version(version) {
+            bail!("invalid mod loader version `{}` in `{}`", version, id);
+        }
+        Ok(VersionSet {
+            minecraft: minecraft.to_string(),
+            loader: version.to_string(),
+            loader_type,
+        })
+    }
+
+    /// manifest.jsonのmodLoaders[].idと同じ形式 (例: forge-47.3.0)
+    pub fn id(&self) -> String {
+        format!("{}-{}", self.loader_type.get_name(), self.loader)
+    }
+
+    pub fn file_name(&self) -> String {
+        format!(
             "{ltype}-{lver}-{mcver}-server_installer.jar",
-            ltype = version_set.loader_type.get_name(),
-            lver = version_set.loader,
-            mcver = version_set.minecraft
-        )),
-    ) {
+            ltype = self.loader_type.get_name(),
+            lver = self.loader,
+            mcver = self.minecraft
+        )
+    }
+
+    /// ロック済みかvendorの目録に同じバージョンがあればそれを、無ければ新しいエントリを返す.
+    pub fn locked_entry(&self, locked: Option<&LockedLoader>, cache: &Cache) -> LockedLoader {
+        let locked = locked
+            .filter(|l| l.id == self.id() && l.minecraft == self.minecraft)
+            .or_else(|| cache.find_loader(&self.id(), &self.minecraft));
+        match locked {
+            Some(l) => l.clone(),
+            None => LockedLoader {
+                id: self.id(),
+                minecraft: self.minecraft.clone(),
+                file_name: self.file_name(),
+                download_url: self.url(),
+                size: 0,
+                sha1: None,
+                sha256: None,
+            },
+        }
+    }
+}
+
+/// ModLoaderのインストーラーを取得する.
+/// ロック済みならそのURLを使い、取得できたらロック用のエントリを返す.
+/// インストーラーの無いローダーは何もせずNoneを返す.
+pub fn fetch_modloader(
+    version_set: &VersionSet,
+    path: &Path,
+    locked: Option<&LockedLoader>,
+    cache: &Cache,
+) -> Option<LockedLoader> {
+    if !version_set.loader_type.has_installer() {
+        info(format!(
+            "{} has no server installer, skipped",
+            version_set.id()
+        ));
+        return None;
+    }
+    let client = Client::new();
+    let mut entry = version_set.locked_entry(locked, cache);
+    let save_path = path.join(&entry.file_name);
+    match cache.fetch(&client, &entry.download_url, &save_path) {
         Ok(()) => info("ModLoader has been installed!"),
-        Err(e) => error(format!("Failed to download ModLoader! :{}", e)),
+        Err(DownloadError::Skipped) => {}
+        Err(e) => {
+            error(format!("Failed to download ModLoader! :{}", e));
+            return None;
+        }
     }
+    let hashes = match hash_file(&save_path) {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            error(format!("Failed to hash ModLoader! :{}", e));
+            return None;
+        }
+    };
+    if let Some(sha256) = &entry.sha256
+        && *sha256 != hashes.sha256
+    {
+        error(format!(
+            "ModLoader sha256 mismatch (expected {}, got {})",
+            sha256, hashes.sha256
+        ));
+        return None;
+    }
+    entry.size = hashes.size;
+    entry.sha1 = Some(hashes.sha1);
+    entry.sha256 = Some(hashes.sha256);
+    Some(entry)
+}
+
+/// `1.20.1`や`47.3.0`、`0.15.11+build.1`のようなバージョンか.
+pub fn is_version(version: &str) -> bool {
+    version.starts_with(|c: char| c.is_ascii_digit())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".-+_".contains(c))
 }
@@ -0,0 +1,168 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, anyhow, bail};
+use reqwest::blocking::Client;
+use serde_json::{Value, json};
+
+use crate::{
+    config::{Config, ManifestJson, ModCommand},
+    curseforge::{find_project, latest_file},
+    logger::{debug, info, warn},
+    modloader::VersionSet,
+};
+
+/// `mod add/remove/update`. manifest.jsonの`files`だけを書き換えて、他のフィールドはそのまま残す.
+pub fn mod_command(
+    command: &ModCommand,
+    config: &Config,
+    manifest: &ManifestJson,
+    offline: bool,
+) -> Result<()> {
+    let path = Path::new(&config.manifest);
+    let text = fs::read_to_string(path)?;
+    let mut value: Value = serde_json::from_str(&text)?;
+    let files = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("{} is not a JSON object", config.manifest))?
+        .entry("files")
+        .or_insert_with(|| json!([]))
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("{}: files is not an array", config.manifest))?;
+
+    let client = Client::new();
+    // removeはローダーを見ないので、壊れたmanifestでも使えるようにここでは読まない
+    let versions = || -> Result<VersionSet> {
+        if offline {
+            bail!("`mod add` and `mod update` need the CurseForge API (--offline)");
+        }
+        VersionSet::from_manifest(manifest)
+    };
+
+    match command {
+        ModCommand::Add {
+            project,
+            version,
+            optional,
+        } => {
+            let versions = versions()?;
+            let project_id = project_id(&client, project, offline)?;
+            let file = latest_file(&client, project_id, &versions, version.as_deref())?;
+            match files.iter_mut().find(|f| entry_id(f) == Some(project_id)) {
+                Some(entry) => {
+                    info(format!(
+                        "projectID={} is already in manifest, changed fileID {} -> {} ({})",
+                        project_id, entry["fileID"], file.file_id, file.file_name
+                    ));
+                    entry["fileID"] = json!(file.file_id);
+                }
+                None => {
+                    files.push(json!({
+                        "projectID": project_id,
+                        "fileID": file.file_id,
+                        "required": !optional,
+                    }));
+                    info(format!(
+                        "Added projectID={} fileID={} ({})",
+                        project_id, file.file_id, file.file_name
+                    ));
+                }
+            }
+        }
+        ModCommand::Remove { projects } => {
+            for project in projects {
+                let project_id = project_id(&client, project, offline)?;
+                let before = files.len();
+                files.retain(|f| entry_id(f) != Some(project_id));
+                if files.len() == before {
+                    bail!("{} is not in {}", project, config.manifest);
+                }
+                info(format!("Removed projectID={}", project_id));
+            }
+        }
+        ModCommand::Update { projects, all } => {
+            let versions = versions()?;
+            let targets = if *all {
+                files.iter().filter_map(entry_id).collect()
+            } else {
+                let mut ids = Vec::new();
+                for project in projects {
+                    let project_id = project_id(&client, project, offline)?;
+                    if !files.iter().any(|f| entry_id(f) == Some(project_id)) {
+                        bail!("{} is not in {}", project, config.manifest);
+                    }
+                    ids.push(project_id);
+                }
+                ids
+            };
+            let mut updated = 0;
+            let mut skipped = Vec::new();
+            for project_id in targets {
+                // 1つ見つからなくても、それまでの更新は捨てずに書き出す
+                let file = match latest_file(&client, project_id, &versions, None) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        warn(format!("skipped projectID={}: {:#}", project_id, e));
+                        skipped.push(project_id);
+                        continue;
+                    }
+                };
+                let Some(entry) = files.iter_mut().find(|f| entry_id(f) == Some(project_id)) else {
+                    continue;
+                };
+                let current = entry["fileID"].as_u64().unwrap_or(0);
+                if u64::from(file.file_id) <= current {
+                    debug(format!("projectID={} is up to date", project_id));
+                    continue;
+                }
+                info(format!(
+                    "Updated projectID={}: fileID {} -> {} ({})",
+                    project_id, current, file.file_id, file.file_name
+                ));
+                entry["fileID"] = json!(file.file_id);
+                updated += 1;
+            }
+            info(format!("{} mods updated", updated));
+            if !skipped.is_empty() {
+                warn(format!(
+                    "{} mods were not updated: projectID {}",
+                    skipped.len(),
+                    skipped
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+    }
+
+    let mut out = serde_json::to_string_pretty(&value)?;
+    if text.ends_with('\n') {
+        out.push('\n');
+    }
+    fs::write(path, out).with_context(|| format!("failed to write {}", config.manifest))?;
+    info(format!("{} written", config.manifest));
+    Ok(())
+}
+
+/// slugかprojectID. 数字だけならprojectIDとして扱う.
+fn project_id(client: &Client, project: &str, offline: bool) -> Result<u32> {
+    if let Ok(id) = project.parse() {
+        return Ok(id);
+    }
+    if offline {
+        bail!(
+            "cannot look up slug `{}` offline, use the projectID",
+            project
+        );
+    }
+    let (id, name) = find_project(client, project)?;
+    debug(format!("{} is projectID={} ({})", project, id, name));
+    Ok(id)
+}
+
+fn entry_id(entry: &Value) -> Option<u32> {
+    entry["projectID"]
+        .as_u64()
+        .and_then(|id| id.try_into().ok())
+}
@@ -0,0 +1,192 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::Local;
+use serde_derive::Serialize;
+
+use crate::{
+    config::{Config, ManifestJson},
+    lockfile::{LockedLoader, LockedMod},
+    logger::info,
+    utils::hash_file,
+};
+
+pub const REPORT_FILE: &str = "build-report.json";
+
+/// `distribution/build-report.json`の中身. デプロイスクリプトが読む用.
+#[derive(Serialize)]
+pub struct BuildReport {
+    #[serde(rename = "generatedAt")]
+    generated_at: String,
+    packs: Vec<PackReport>,
+}
+
+/// プロファイル1つ分のビルド結果.
+#[derive(Serialize)]
+pub struct PackReport {
+    profile: Option<String>,
+    name: String,
+    version: String,
+    author: String,
+    minecraft: String,
+    loader: Option<LoaderReport>,
+    artifacts: Vec<Artifact>,
+    mods: Vec<ModReport>,
+}
+
+#[derive(Serialize)]
+struct LoaderReport {
+    id: String,
+    #[serde(rename = "fileName")]
+    file_name: String,
+    sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Artifact {
+    kind: &'static str,
+    /// `distribution`からの相対パス
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct ModReport {
+    #[serde(rename = "projectID")]
+    project_id: u32,
+    #[serde(rename = "fileID")]
+    file_id: u32,
+    name: Option<String>,
+    #[serde(rename = "fileName")]
+    file_name: String,
+    server: Decision,
+    client: Decision,
+}
+
+/// そのパックに入れたかどうかと、その理由.
+#[derive(Serialize)]
+struct Decision {
+    included: bool,
+    reason: String,
+}
+
+impl Decision {
+    fn new(included: bool, reason: &str) -> Decision {
+        Decision {
+            included,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl PackReport {
+    /// `server_jars`はサーバーパックに実際に入れたjar.
+    pub fn new(
+        profile: Option<&str>,
+        config: &Config,
+        manifest: &ManifestJson,
+        loader: Option<&LockedLoader>,
+        resolved: &[LockedMod],
+        server_jars: &[PathBuf],
+    ) -> PackReport {
+        let mods = resolved
+            .iter()
+            .map(|m| {
+                let optional = manifest
+                    .files
+                    .iter()
+                    .flatten()
+                    .any(|f| f.project_id == m.project_id && !f.required);
+                let server = if config
+                    .default_config
+                    .no_needs_with_server
+                    .contains(&m.project_id)
+                {
+                    Decision::new(false, "client-only (default_config.no_needs_with_server)")
+                } else if config
+                    .additional_noneeds_with_server
+                    .contains(&m.project_id)
+                {
+                    Decision::new(false, "client-only (additional_noneeds_with_server)")
+                } else if server_jars
+                    .iter()
+                    .any(|p| p.file_name().is_some_and(|n| *n == *m.file_name))
+                {
+                    Decision::new(true, "listed in manifest")
+                } else {
+                    Decision::new(false, "download failed or hash mismatch")
+                };
+                // クライアントはmanifest.jsonのfilesをランチャーが落とすので全部入る
+                let client = if optional {
+                    Decision::new(true, "listed in manifest (optional)")
+                } else {
+                    Decision::new(true, "listed in manifest")
+                };
+                ModReport {
+                    project_id: m.project_id,
+                    file_id: m.file_id,
+                    name: m.name.clone(),
+                    file_name: m.file_name.clone(),
+                    server,
+                    client,
+                }
+            })
+            .collect();
+
+        PackReport {
+            profile: profile.map(str::to_string),
+            name: config.info.name.clone(),
+            version: config.info.version.clone(),
+            author: config.info.author.clone(),
+            minecraft: manifest.minecraft.version.clone(),
+            loader: loader.map(|l| LoaderReport {
+                id: l.id.clone(),
+                file_name: l.file_name.clone(),
+                sha256: l.sha256.clone(),
+            }),
+            artifacts: Vec::new(),
+            mods,
+        }
+    }
+
+    /// 書き出したアーカイブを足す. ステージを飛ばしたときも前回の成果物を載せる.
+    pub fn add_artifact(
+        &mut self,
+        kind: &'static str,
+        path: &Path,
+        outputfolder: &Path,
+    ) -> Result<()> {
+        let hashes = hash_file(path)?;
+        let rel = path.strip_prefix(outputfolder).unwrap_or(path);
+        self.artifacts.push(Artifact {
+            kind,
+            path: rel
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+            size: hashes.size,
+            sha256: hashes.sha256,
+        });
+        Ok(())
+    }
+}
+
+/// ビルドした全パックの結果を`outputfolder/build-report.json`に書く.
+pub fn write_report(outputfolder: &Path, packs: Vec<PackReport>) -> Result<()> {
+    let report = BuildReport {
+        generated_at: Local::now().to_rfc3339(),
+        packs,
+    };
+    let path = outputfolder.join(REPORT_FILE);
+    fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+    info(format!(
+        "Build report written to {}",
+        path.to_string_lossy()
+    ));
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use globset::GlobSet;
+
+use crate::{
+    config::{Config, ManifestJson},
+    filter::build_globset,
+    logger::{debug, warn},
+};
+
+/// override内のファイルの`{{pack.version}}`などを置き換える.
+/// 対象は`template_files`のパターンに当たるファイルだけ.
+pub struct Templater {
+    matcher: GlobSet,
+    vars: BTreeMap<&'static str, String>,
+}
+
+impl Templater {
+    pub fn new(
+        config: &Config,
+        manifest: &ManifestJson,
+        profile: Option<&str>,
+    ) -> Result<Templater> {
+        let loader_id = manifest
+            .minecraft
+            .mod_loaders
+            .iter()
+            .find(|l| l.primary)
+            .or(manifest.minecraft.mod_loaders.first())
+            .map(|l| l.id.clone())
+            .unwrap_or_default();
+        let (loader_type, loader_version) = loader_id.split_once('-').unwrap_or(("", ""));
+
+        let vars = BTreeMap::from([
+            ("pack.name", config.info.name.clone()),
+            ("pack.version", config.info.version.clone()),
+            ("pack.author", config.info.author.clone()),
+            ("pack.profile", profile.unwrap_or_default().to_string()),
+            ("mc.version", manifest.minecraft.version.clone()),
+            ("loader.id", loader_id.clone()),
+            ("loader.type", loader_type.to_string()),
+            ("loader.version", loader_version.to_string()),
+        ]);
+        Ok(Templater {
+            matcher: build_globset(&config.template_files)?,
+            vars,
+        })
+    }
+
+    /// `path`はカレントディレクトリからのコピー元のパス.
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.matcher
+            .is_match(path.strip_prefix("./").unwrap_or(path))
+    }
+
+    /// 置き換えてから`to`に書く.
+    pub fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::write(to, self.render_file(from)?)?;
+        // fs::copyと同じく実行権限などを引き継ぐ
+        fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+        Ok(())
+    }
+
+    /// 置き換えた中身. パックに入る内容と比べたいときにも使う.
+    /// `fancymenu/**`のように画像も当たるパターンがあるので、テキストでなければそのまま返す.
+    pub fn render_file(&self, from: &Path) -> Result<Vec<u8>> {
+        let data = fs::read(from).with_context(|| format!("failed to read {:?}", from))?;
+        match String::from_utf8(data) {
+            Ok(text) => Ok(self.render(&text, from).into_bytes()),
+            Err(e) => {
+                debug(format!("{:?} is not a utf-8 text file, copied as is", from));
+                Ok(e.into_bytes())
+            }
+        }
+    }
+
+    fn render(&self, text: &str, from: &Path) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start..].find("}}") else {
+                break;
+            };
+            let key = rest[start + 2..start + len].trim();
+            out += &rest[..start];
+            match self.vars.get(key) {
+                Some(value) => out += value,
+                None => {
+                    // 知らない変数はそのまま残す
+                    warn(format!("unknown template variable `{}` in {:?}", key, from));
+                    out += &rest[start..start + len + 2];
+                }
+            }
+            rest = &rest[start + len + 2..];
+        }
+        out += rest;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templater() -> Templater {
+        Templater {
+            matcher: build_globset(&["config/**".to_string()]).unwrap(),
+            vars: BTreeMap::from([
+                ("pack.name", "TestPack".to_string()),
+                ("pack.version", "1.0.2".to_string()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn render_replaces_known_vars() {
+        let out = templater().render("{{pack.name}} v{{ pack.version }}", Path::new("a"));
+        assert_eq!(out, "TestPack v1.0.2");
+    }
+
+    #[test]
+    fn render_keeps_unknown_vars_and_unclosed_braces() {
+        let t = templater();
+        assert_eq!(
+            t.render("{{mc.unknown}} {{pack.name}}", Path::new("a")),
+            "{{mc.unknown}} TestPack"
+        );
+        assert_eq!(t.render("a {{pack.name", Path::new("a")), "a {{pack.name");
+    }
+
+    #[test]
+    fn is_match_ignores_leading_dot_slash() {
+        let t = templater();
+        assert!(t.is_match(Path::new("./config/a.toml")));
+        assert!(t.is_match(Path::new("config/sub/a.toml")));
+        assert!(!t.is_match(Path::new("kubejs/a.js")));
+    }
+
+    #[test]
+    fn render_file_keeps_binary_as_is() {
+        let path = std::env::temp_dir().join(format!("mcpack-template-{}.png", std::process::id()));
+        let data = vec![0x89, b'P', b'N', b'G', 0xff, 0xfe, b'{', b'{'];
+        fs::write(&path, &data).unwrap();
+        let rendered = templater().render_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rendered.unwrap(), data);
+    }
+}
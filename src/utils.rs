@@ -1,28 +1,31 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow, bail};
 use bytes::Bytes;
 use core::fmt;
 use rayon::iter::{ParallelBridge, ParallelIterator};
 use reqwest::blocking::Client;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use std::{
     fs::{self, File},
-    io::{Read, Write},
-    path::{Path, PathBuf},
+    io::Read,
+    path::{Component, Path, PathBuf},
 };
 use walkdir::WalkDir;
-use zip::{
-    ZipWriter,
-    write::{ExtendedFileOptions, FileOptions},
-};
 
 use crate::config::{Config, ManifestJson};
 use crate::curseforge::retry;
-use crate::logger::{error, info, warn};
+use crate::filter::PathFilter;
+use crate::logger::{debug, error, info, warn};
+use crate::template::Templater;
 
 #[derive(Debug)]
 pub enum DownloadError {
     Skipped,
     RetryFault,
+    CopyFault,
+    Offline,
 }
 
 impl fmt::Display for DownloadError {
@@ -30,6 +33,8 @@ impl fmt::Display for DownloadError {
         match self {
             DownloadError::Skipped => write!(f, "Download skipped"),
             DownloadError::RetryFault => write!(f, "retryed few times"),
+            DownloadError::CopyFault => write!(f, "failed to copy from cache"),
+            DownloadError::Offline => write!(f, "not found in local cache (offline)"),
         }
     }
 }
@@ -39,26 +44,111 @@ impl std::error::Error for DownloadError {}
 pub fn read_manifest_json(path: &Path) -> Result<ManifestJson> {
     // JSON向けにBufReaderの実装が存在するが精々600要素程度.
     // かつ、100KB程度と思われるので直に読み込む.
-    let raw_data = fs::read_to_string(path)?;
-    let manifest = serde_json::from_str(&raw_data).context("failed to parse manifest json")?;
-    Ok(manifest)
+    let raw_data = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+    serde_json::from_str(&raw_data).map_err(|e| {
+        // serde_jsonのメッセージは末尾に" at line X column Y"が付くので外して前に出す
+        let msg = e.to_string();
+        let msg = msg
+            .rsplit_once(" at line ")
+            .map_or(msg.as_str(), |(m, _)| m);
+        anyhow!(
+            "{}:{}:{}: {}",
+            path.to_string_lossy(),
+            e.line(),
+            e.column(),
+            msg
+        )
+    })
 }
 
-pub fn read_config(path: &Path) -> Result<Config> {
+/// 設定を読む. 知らないキーは警告し、`strict`(か設定の`strict = true`)ならエラーにする.
+pub fn read_config(path: &Path, strict: bool) -> Result<Config> {
     // TOMLは小さいことがわかっているので直にやる
-    let raw_data = fs::read_to_string(path)?;
-    let config = toml::from_str(&raw_data).context("failed to parse config toml")?;
+    let raw_data = fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+    let mut unknown = Vec::new();
+    let config: Config = toml::Deserializer::parse(&raw_data)
+        .and_then(|de| serde_ignored::deserialize(de, |key| unknown.push(key.to_string())))
+        .map_err(|e| {
+            let (line, column) = e
+                .span()
+                .map(|span| line_column(&raw_data, span.start))
+                .unwrap_or((0, 0));
+            anyhow!(
+                "{}:{}:{}: {}",
+                path.to_string_lossy(),
+                line,
+                column,
+                e.message()
+            )
+        })?;
+
+    for key in &unknown {
+        let msg = format!("{}: unknown key `{}`", path.to_string_lossy(), key);
+        if strict || config.strict {
+            error(msg);
+        } else {
+            warn(msg);
+        }
+    }
+    if (strict || config.strict) && !unknown.is_empty() {
+        bail!(
+            "{} has {} unknown keys (strict mode)",
+            path.to_string_lossy(),
+            unknown.len()
+        );
+    }
     Ok(config)
 }
 
+// バイト位置を1始まりの行と列にする
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+/// config.tomlのJSON Schemaを書き出す.
+pub fn write_schema(path: &Path) -> Result<()> {
+    let schema = schemars::schema_for!(Config);
+    fs::write(path, serde_json::to_string_pretty(&schema)?)?;
+    info(format!("JSON Schema written to {}", path.to_string_lossy()));
+    Ok(())
+}
+
+pub struct FileHashes {
+    pub size: u64,
+    pub sha1: String,
+    pub sha256: String,
+}
+
+/// ファイルのサイズとハッシュを計算する.
+pub fn hash_file(path: &Path) -> Result<FileHashes> {
+    let mut file = File::open(path)?;
+    let mut sha1 = Sha1::new();
+    let mut sha256 = Sha256::new();
+    let mut size = 0u64;
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        sha1.update(&buffer[..n]);
+        sha256.update(&buffer[..n]);
+        size += n as u64;
+    }
+    Ok(FileHashes {
+        size,
+        sha1: format!("{:x}", sha1.finalize()),
+        sha256: format!("{:x}", sha256.finalize()),
+    })
+}
+
 fn retryable_fetch(client: &Client, download_url: &String) -> Result<Bytes, reqwest::Error> {
-    let resp = client
-        .get(download_url)
-        .send()
-        .expect("Request Failed")
-        .bytes()
-        .unwrap();
-    Ok(resp)
+    client.get(download_url).send()?.bytes()
 }
 
 pub fn fetch_file(
@@ -92,7 +182,49 @@ pub fn fetch_file(
     Ok(())
 }
 
-pub fn copy_dir(from: &Path, to: &Path) -> Result<bool> {
+/// ディレクトリを空にする. 無ければ作る.
+pub fn reset_dir(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path)
+            .with_context(|| format!("failed to clean {}", path.to_string_lossy()))?;
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+/// 単一ファイルのコピー. コピー先のディレクトリが無ければ作る.
+pub fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(from, to).with_context(|| format!("failed to copy {:?} to {:?}", from, to))?;
+    Ok(())
+}
+
+/// `./config`のような設定上のパスをパック内の相対パスにする.
+/// パックの外を指すパスはエラーにする.
+pub fn pack_relative(path: &str) -> Result<PathBuf> {
+    let mut rel = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(c) => rel.push(c),
+            _ => bail!("{} points outside of the pack", path),
+        }
+    }
+    Ok(rel)
+}
+
+/// `from`の中身を`to`にコピーする. `layer`はどのレイヤーからのコピーかをログに出すための名前.
+/// 既にあるファイルは上書きする(先にコピーしたレイヤーより優先される).
+/// `templater`のパターンに当たるファイルは変数を置き換えてからコピーする.
+pub fn copy_dir(
+    from: &Path,
+    to: &Path,
+    filter: &PathFilter,
+    templater: &Templater,
+    layer: &str,
+) -> Result<bool> {
     let has_skiped = true;
 
     fs::create_dir_all(to)?;
@@ -112,6 +244,7 @@ pub fn copy_dir(from: &Path, to: &Path) -> Result<bool> {
     }
     fs::create_dir_all(to)?;
 
+    let failed = AtomicUsize::new(0);
     WalkDir::new(from)
         .into_iter()
         .filter_map(|e| e.ok())
@@ -121,66 +254,58 @@ pub fn copy_dir(from: &Path, to: &Path) -> Result<bool> {
 
             if from_path.is_file() {
                 let rel = from_path.strip_prefix(from).unwrap();
+                if !filter.is_match(rel) {
+                    debug(format!(
+                        "[{}] exclude {}",
+                        layer,
+                        from_path.to_string_lossy()
+                    ));
+                    return;
+                }
                 let to_path = Path::new(&to).join(rel);
                 if let Some(parent) = to_path.parent() {
                     fs::create_dir_all(parent).expect("Error in Create directory");
                 }
-                info(format!(
-                    "copy {} to {}",
-                    from_path.to_string_lossy(),
-                    to_path.to_string_lossy()
-                ));
+                if to_path.exists() {
+                    debug(format!(
+                        "[{}] override {} with {}",
+                        layer,
+                        to_path.to_string_lossy(),
+                        from_path.to_string_lossy()
+                    ));
+                } else {
+                    debug(format!(
+                        "[{}] copy {} to {}",
+                        layer,
+                        from_path.to_string_lossy(),
+                        to_path.to_string_lossy()
+                    ));
+                }
 
-                if let Err(e) = fs::copy(from_path, &to_path) {
+                let copied = if templater.is_match(from_path) {
+                    debug(format!("[{}] render template {:?}", layer, from_path));
+                    templater.copy(from_path, &to_path)
+                } else {
+                    fs::copy(from_path, &to_path)
+                        .map(|_| ())
+                        .map_err(Into::into)
+                };
+                if let Err(e) = copied {
                     error(format!(
-                        "Failed to copy {:?} | {:?} :{}",
+                        "Failed to copy {:?} | {:?} :{:#}",
                         from_path, to_path, e
                     ));
+                    failed.fetch_add(1, Ordering::Relaxed);
                 }
             }
         });
+    // 1つでも欠けたパックを成功扱いにしない
+    let failed = failed.into_inner();
+    if failed > 0 {
+        bail!("failed to copy {} files from {:?}", failed, from);
+    }
 
     info(format!("Copy {:?} to {:?} is Successful.", from, to));
 
     Ok(!has_skiped)
 }
-
-pub fn directory_archive(directory_path: &PathBuf, archive_name: &PathBuf) -> Result<()> {
-    let file = File::create(archive_name)?;
-    let mut zip = ZipWriter::new(&file);
-    let opts: FileOptions<ExtendedFileOptions> = FileOptions::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .unix_permissions(0o755)
-        .compression_level(Some(3));
-    let mut buffer = Vec::new();
-
-    // walkDirで再帰処理
-    WalkDir::new(directory_path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .for_each(|entry| {
-            let path = entry.path();
-            let name = path.strip_prefix(directory_path).unwrap();
-
-            if path.is_file() {
-                info(format!("adding file {:?} as {:?}", path, name));
-                zip.start_file(name.to_string_lossy(), opts.clone())
-                    .unwrap();
-                let mut f = File::open(path).unwrap();
-                f.read_to_end(&mut buffer).unwrap();
-                zip.write_all(&buffer).unwrap();
-                buffer.clear();
-            } else if !name.as_os_str().is_empty() {
-                info(format!("create dir {:?}", name));
-                zip.add_directory(name.to_string_lossy(), opts.clone())
-                    .unwrap();
-            }
-        });
-
-    zip.finish().unwrap();
-    info(format!(
-        "Created Archive {:?}",
-        archive_name.to_string_lossy()
-    ));
-    Ok(())
-}
@@ -0,0 +1,287 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{Result, bail};
+use serde_json::Value;
+use zip::ZipArchive;
+
+use crate::{
+    build::Target,
+    config::{Config, ManifestJson, same_path},
+    logger::{error, info, warn},
+    modloader::VersionSet,
+    utils::pack_relative,
+};
+
+/// CurseForgeのランチャーが読み込めるクライアントzipかを確認する.
+/// 問題があれば全部ログに出してからエラーにする.
+pub fn validate_client_zip(path: &Path) -> Result<()> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut issues = Vec::new();
+
+    let manifest: Option<Value> = match archive.by_name("manifest.json") {
+        Ok(mut entry) => {
+            let mut raw_data = String::new();
+            entry.read_to_string(&mut raw_data)?;
+            match serde_json::from_str(&raw_data) {
+                Ok(manifest) => Some(manifest),
+                Err(e) => {
+                    issues.push(format!("manifest.json is not valid json: {}", e));
+                    None
+                }
+            }
+        }
+        Err(_) => {
+            issues.push("manifest.json is not at the root of the zip".to_string());
+            None
+        }
+    };
+
+    let mut overrides = "overrides".to_string();
+    if let Some(manifest) = &manifest {
+        if manifest["manifestType"] != "minecraftModpack" {
+            issues.push("manifestType must be \"minecraftModpack\"".to_string());
+        }
+        if manifest["manifestVersion"] != 1 {
+            issues.push("manifestVersion must be 1".to_string());
+        }
+        match manifest["overrides"].as_str() {
+            Some(o) if !o.is_empty() && !o.contains(['/', '\\']) => overrides = o.to_string(),
+            _ => issues.push("overrides must be a plain folder name".to_string()),
+        }
+        if manifest["minecraft"]["version"]
+            .as_str()
+            .is_none_or(|v| v.is_empty())
+        {
+            issues.push("minecraft.version is empty".to_string());
+        }
+        let loaders = manifest["minecraft"]["modLoaders"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let primaries = loaders.iter().filter(|l| l["primary"] == true).count();
+        if loaders.is_empty() {
+            issues.push("minecraft.modLoaders is empty".to_string());
+        } else if primaries != 1 {
+            issues.push(format!(
+                "minecraft.modLoaders must have exactly one primary loader (found {})",
+                primaries
+            ));
+        }
+        for (i, f) in manifest["files"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+        {
+            if f["projectID"].as_u64().is_none_or(|id| id == 0)
+                || f["fileID"].as_u64().is_none_or(|id| id == 0)
+            {
+                issues.push(format!("files[{}] has no valid projectID/fileID", i));
+            }
+        }
+    }
+
+    // ランチャーはmanifest.json/modlistとoverrideフォルダの中身しか見ない
+    let prefix = format!("{}/", overrides);
+    for name in archive.file_names() {
+        if name.contains('\\') || name.starts_with('/') || name.split('/').any(|c| c == "..") {
+            issues.push(format!("invalid entry path: {}", name));
+        } else if !(name.starts_with(&prefix)
+            || name == prefix.trim_end_matches('/')
+            || name == "manifest.json"
+            || name.starts_with("modlist."))
+        {
+            issues.push(format!("{} is outside of {}/", name, overrides));
+        }
+    }
+
+    if !issues.is_empty() {
+        for issue in &issues {
+            error(format!("{}: {}", path.to_string_lossy(), issue));
+        }
+        bail!(
+            "{} can not be imported by the launcher ({} issues)",
+            path.to_string_lossy(),
+            issues.len()
+        );
+    }
+    info(format!("Validated {}", path.to_string_lossy()));
+    Ok(())
+}
+
+/// ビルド前に設定とmanifestの中身を確認した結果.
+/// errorsがあればビルドしない. warningsは出すだけ.
+#[derive(Default)]
+pub struct CheckResult {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl CheckResult {
+    /// 全部ログに出して、エラーがあれば失敗にする.
+    pub fn report(&self) -> Result<()> {
+        for w in &self.warnings {
+            warn(w);
+        }
+        for e in &self.errors {
+            error(e);
+        }
+        if !self.errors.is_empty() {
+            bail!(
+                "config check failed ({} errors, {} warnings)",
+                self.errors.len(),
+                self.warnings.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// 設定とmanifestの意味的なチェック. プロファイルを適用した各ビルド対象も見る.
+pub fn check_project(config: &Config, manifest: &ManifestJson) -> CheckResult {
+    let mut result = CheckResult::default();
+    let errors = &mut result.errors;
+    let warnings = &mut result.warnings;
+
+    // パック名とバージョンはzipのファイル名になる
+    for (key, value) in [
+        ("info.name", &config.info.name),
+        ("info.version", &config.info.version),
+    ] {
+        if value.trim().is_empty() {
+            errors.push(format!("{} is empty", key));
+        } else if value.contains(['/', '\\', ':', '*', '?', '"', '<', '>', '|']) {
+            errors.push(format!(
+                "{} `{}` contains characters not allowed in file names",
+                key, value
+            ));
+        }
+    }
+
+    let mc = &manifest.minecraft.version;
+    if !mc
+        .split('.')
+        .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+        || !(2..=3).contains(&mc.split('.').count())
+    {
+        errors.push(format!(
+            "minecraft.version `{}` is not a release version like 1.20.1",
+            mc
+        ));
+    }
+    let loaders = &manifest.minecraft.mod_loaders;
+    if loaders.is_empty() {
+        errors.push("minecraft.modLoaders is empty".to_string());
+    }
+    for loader in loaders {
+        if let Err(e) = VersionSet::parse(mc, &loader.id) {
+            errors.push(e.to_string());
+        }
+    }
+    let primaries = loaders.iter().filter(|l| l.primary).count();
+    if !loaders.is_empty() && primaries != 1 {
+        errors.push(format!(
+            "minecraft.modLoaders must have exactly one primary loader (found {})",
+            primaries
+        ));
+    }
+
+    check_profiles(config, manifest, warnings);
+
+    match Target::list(config, manifest, None) {
+        Ok(targets) => {
+            for target in &targets {
+                let label = target
+                    .profile
+                    .as_deref()
+                    .map_or("manifest".to_string(), |p| format!("profile {}", p));
+                let mut seen = Vec::new();
+                for m in target.manifest.files.iter().flatten() {
+                    if m.project_id == 0 || m.file_id == 0 {
+                        errors.push(format!(
+                            "{}: projectID={} fileID={} is not a valid file",
+                            label, m.project_id, m.file_id
+                        ));
+                    }
+                    if seen.contains(&m.project_id) {
+                        errors.push(format!(
+                            "{}: projectID={} is listed more than once",
+                            label, m.project_id
+                        ));
+                    } else {
+                        seen.push(m.project_id);
+                    }
+                }
+                check_paths(&target.config, errors, warnings);
+            }
+        }
+        Err(e) => errors.push(e.to_string()),
+    }
+    // プロファイルはベースのパスを引き継ぐので同じ指摘が何度も出る
+    for list in [errors, warnings] {
+        let mut seen = Vec::new();
+        list.retain(|issue| {
+            let first = !seen.contains(issue);
+            seen.push(issue.clone());
+            first
+        });
+    }
+    result
+}
+
+/// プロファイルの`remove_*`が何にも当たっていないと、書き間違えても全部入りのパックができてしまう.
+fn check_profiles(config: &Config, manifest: &ManifestJson, warnings: &mut Vec<String>) {
+    for (name, profile) in &config.profiles {
+        for id in &profile.remove_mods {
+            if !manifest.files.iter().flatten().any(|m| m.project_id == *id) {
+                warnings.push(format!(
+                    "profile {}: remove_mods {} is not in manifest",
+                    name, id
+                ));
+            }
+        }
+        for (key, removes, list) in [
+            (
+                "remove_override_dirs",
+                &profile.remove_override_dirs,
+                &config.override_dirs,
+            ),
+            (
+                "remove_additional_copy_files",
+                &profile.remove_additional_copy_files,
+                &config.additional_copy_files,
+            ),
+        ] {
+            for r in removes {
+                if !list.iter().any(|v| same_path(v, r)) {
+                    warnings.push(format!("profile {}: {} `{}` matches nothing", name, key, r));
+                }
+            }
+        }
+    }
+}
+
+fn check_paths(config: &Config, errors: &mut Vec<String>, warnings: &mut Vec<String>) {
+    // 無いoverrideはコピー時に飛ばされるだけなので警告にとどめる
+    for (key, dirs) in [
+        ("override_dirs", &config.override_dirs),
+        ("server_overrides", &config.server_overrides),
+        ("client_overrides", &config.client_overrides),
+    ] {
+        for dir in dirs {
+            if let Err(e) = pack_relative(dir) {
+                errors.push(format!("{}: {}", key, e));
+            } else if !Path::new(dir).is_dir() {
+                warnings.push(format!("{} `{}` does not exist", key, dir));
+            }
+        }
+    }
+    for f in &config.additional_copy_files {
+        if let Err(e) = pack_relative(f) {
+            errors.push(format!("additional_copy_files: {}", e));
+        } else if !Path::new(f).is_file() {
+            errors.push(format!("additional_copy_files `{}` does not exist", f));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    build::Target,
+    cache::Cache,
+    curseforge::{fetchmods, resolve_mods},
+    lockfile::{LockFile, LockedLoader, LockedMod},
+    logger::{error, info},
+    modloader::{VersionSet, fetch_modloader},
+};
+
+pub const INDEX_FILE: &str = "index.json";
+
+/// vendorディレクトリの目録.
+/// `cache_dirs`に登録すると、ロックファイルが無くてもここからMODを解決できる.
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct VendorIndex {
+    #[serde(default)]
+    pub mods: Vec<LockedMod>,
+    #[serde(default)]
+    pub loaders: Vec<LockedLoader>,
+}
+
+impl VendorIndex {
+    pub fn read(dir: &Path) -> Result<Option<VendorIndex>> {
+        let path = dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let raw_data = fs::read_to_string(&path)?;
+        let index = serde_json::from_str(&raw_data)
+            .with_context(|| format!("failed to parse {}", path.to_string_lossy()))?;
+        Ok(Some(index))
+    }
+}
+
+/// 全プロファイルのMODとModLoaderを`dir`にダウンロードして目録を書く.
+/// クライアント専用MODも含めて全部落とす.
+pub fn vendor(targets: &[Target], lock: &mut LockFile, cache: &Cache, dir: &Path) -> Result<()> {
+    let mut mods = Vec::new();
+    for target in targets {
+        for m in target.manifest.files.iter().flatten() {
+            if !mods.contains(m) {
+                mods.push(m.clone());
+            }
+        }
+    }
+
+    let mut resolved = resolve_mods(&mods, lock, cache)?;
+    let fetched = fetchmods(&mut resolved, dir, &[], cache)?;
+    lock.merge_mods(&resolved);
+    let failed: Vec<_> = resolved
+        .iter()
+        .filter(|m| !fetched.contains(&dir.join("mods").join(&m.file_name)))
+        .collect();
+    for m in &failed {
+        error(format!(
+            "{} (projectID={} fileID={}) could not be vendored",
+            m.file_name, m.project_id, m.file_id
+        ));
+    }
+
+    let loader_dir = dir.join("loader");
+    fs::create_dir_all(&loader_dir)?;
+    let mut loaders: Vec<LockedLoader> = Vec::new();
+    for target in targets {
+        let versionset = VersionSet::from_manifest(&target.manifest)?;
+        // Fabricのようにインストーラーが無いものは持ち込むファイルも無い
+        if !versionset.loader_type.has_installer()
+            || loaders.iter().any(|l| l.id == versionset.id())
+        {
+            continue;
+        }
+        match fetch_modloader(&versionset, &loader_dir, lock.loader.as_ref(), cache) {
+            Some(loader) => {
+                lock.loader = Some(loader.clone());
+                loaders.push(loader);
+            }
+            None => bail!("failed to vendor mod loader {}", versionset.id()),
+        }
+    }
+    if !failed.is_empty() {
+        bail!("{} mods could not be vendored", failed.len());
+    }
+
+    let index = VendorIndex {
+        mods: resolved,
+        loaders,
+    };
+    fs::write(dir.join(INDEX_FILE), serde_json::to_string_pretty(&index)?)?;
+    info(format!(
+        "Vendored {} mods and {} loaders to {}",
+        index.mods.len(),
+        index.loaders.len(),
+        dir.to_string_lossy()
+    ));
+    Ok(())
+}